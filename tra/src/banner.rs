@@ -1,6 +1,12 @@
-use std::path::Path;
+use std::{io::Write, path::Path, time::Duration};
 
-use crate::replica::path_local::PathLocal;
+use crate::{
+    centra::{Convergence, ReplicaHealth},
+    config::Link,
+    machine::ServeAddr,
+    replica::path_local::PathLocal,
+    reptra::{notifier::Subscriber, Progress, StatusRes, SyncAction, SyncReport},
+};
 
 pub struct BannerOut;
 
@@ -8,6 +14,12 @@ pub struct LocalBanner;
 
 pub struct SyncBanner;
 
+pub struct StatusBanner;
+
+pub struct RegistryBanner;
+
+pub struct NotifyBanner;
+
 impl BannerOut {
     pub fn check(msg: impl AsRef<str>) {
         println!("✔  {}", msg.as_ref());
@@ -57,6 +69,35 @@ impl LocalBanner {
         ));
     }
 
+    pub fn watch_failed(path: impl AsRef<Path>, err: impl std::fmt::Display) {
+        BannerOut::warn(format!(
+            "Local Watch: \"{}\" failed ({}), polling instead",
+            path.as_ref().display(),
+            err
+        ));
+    }
+
+    pub fn reconstruct(path: &PathLocal) {
+        BannerOut::warn(format!(
+            "Local Event: \"{}\" missing in the tree, reconstructed from disk",
+            path.display()
+        ));
+    }
+
+    pub fn unseen(path: &PathLocal) {
+        BannerOut::warn(format!(
+            "Local Event: \"{}\" changed without an event, recorded now",
+            path.display()
+        ));
+    }
+
+    pub fn drop_event(path: &PathLocal) {
+        BannerOut::warn(format!(
+            "Local Event: \"{}\" missing in the tree and on disk, event dropped",
+            path.display()
+        ));
+    }
+
     pub fn create(parent: &PathLocal, name: &String) {
         BannerOut::event(format!(
             "Local Creation: \"{}\" in \"{}\"",
@@ -75,17 +116,225 @@ impl LocalBanner {
 }
 
 impl SyncBanner {
-    pub fn sync_request(id1: i32, port1: u16, id2: i32, prot2: u16, path: impl AsRef<Path>) {
+    pub fn sync_request(
+        id1: i32,
+        addr1: &ServeAddr,
+        id2: i32,
+        addr2: &ServeAddr,
+        path: impl AsRef<Path>,
+    ) {
         BannerOut::new_sync(format!(
             "Sync Request : replica-{}({}) -> replica-{}({}), path = \"{}\"",
             id1,
-            port1,
+            addr1,
             id2,
-            prot2,
+            addr2,
             path.as_ref().display()
         ));
     }
 
+    pub fn clone_request(id1: i32, addr1: &ServeAddr, id2: i32, addr2: &ServeAddr, root: &str) {
+        BannerOut::new_sync(format!(
+            "Clone Request : replica-{}({}) -> replica-{}({}), path = \"{}\"",
+            id1,
+            addr1,
+            id2,
+            addr2,
+            sync_path(root, "")
+        ));
+    }
+
+    pub fn session(session: &str) {
+        BannerOut::check(format!(
+            "Sync Session : {}, Ctrl-C or `cancel {}` stops it at the next file",
+            session, session
+        ));
+    }
+
+    // one line drawn over and over, the total is unknown until the sync is done
+    pub fn progress(progress: &Progress, tick: usize) {
+        const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+        let path = match progress.path.char_indices().rev().nth(39) {
+            Some((at, _)) => format!("...{}", &progress.path[at..]),
+            None => progress.path.clone(),
+        };
+        print!(
+            "\r\x1b[2K{} Syncing : {} nodes, {} files, {} bytes, \"{}\"",
+            SPINNER[tick % SPINNER.len()],
+            progress.nodes,
+            progress.files,
+            progress.bytes,
+            path
+        );
+        let _ = std::io::stdout().flush();
+    }
+
+    // left above the prompt, the progress line is paused until it is answered
+    pub fn conflicts_pending(progress: &Progress) {
+        print!("\r\x1b[2K");
+        BannerOut::warn(format!(
+            "Sync Paused : {} conflicts pending, at \"{}\" after {} nodes, {} files, {} bytes",
+            progress.conflicts, progress.path, progress.nodes, progress.files, progress.bytes
+        ));
+    }
+
+    pub fn progress_clear() {
+        print!("\r\x1b[2K");
+        let _ = std::io::stdout().flush();
+    }
+
+    pub fn cancelled(id: i32, session: &str) {
+        BannerOut::check(format!(
+            "Sync Cancel : session {} on replica-{} stops at the next file",
+            session, id
+        ));
+    }
+
+    pub fn report(report: &SyncReport) {
+        for entry in &report.entries {
+            let action = match SyncAction::from_i32(entry.action) {
+                Some(SyncAction::Created) => "created",
+                Some(SyncAction::Overwritten) => "overwritten",
+                Some(SyncAction::Deleted) => "deleted",
+                Some(SyncAction::Skipped) => "skipped",
+                Some(SyncAction::Conflict) => "conflict",
+                Some(SyncAction::Aborted) => "aborted",
+                None => "unknown",
+            };
+            let path = match entry.path_rel.as_str() {
+                "" => ".",
+                path => path,
+            };
+            let transfer = match entry.file_bytes {
+                0 => String::new(),
+                size => format!(", {} of {} bytes", entry.delta_bytes, size),
+            };
+            println!(
+                "   {:<12}\"{}\" ({}{})",
+                action, path, entry.detail, transfer
+            );
+        }
+        if report.dry_run {
+            BannerOut::check(format!(
+                "Sync Plan : {} to create, {} to overwrite, {} to delete, {} to skip, {} conflicts, nothing is changed",
+                report.count(SyncAction::Created),
+                report.count(SyncAction::Overwritten),
+                report.count(SyncAction::Deleted),
+                report.count(SyncAction::Skipped),
+                report.count(SyncAction::Conflict),
+            ));
+            return;
+        }
+        BannerOut::check(format!(
+            "Sync Report : {} created, {} overwritten, {} deleted, {} skipped, {} conflicts, {} aborted, {} of {} bytes transferred in {} ms",
+            report.count(SyncAction::Created),
+            report.count(SyncAction::Overwritten),
+            report.count(SyncAction::Deleted),
+            report.count(SyncAction::Skipped),
+            report.count(SyncAction::Conflict),
+            report.count(SyncAction::Aborted),
+            report.delta_bytes(),
+            report.file_bytes(),
+            report.duration_ms
+        ));
+        if !report.stopped.is_empty() {
+            BannerOut::warn(format!(
+                "Sync Stopped : session {} {}, the rest is left for the next sync",
+                report.session, report.stopped
+            ));
+        }
+    }
+
+    pub fn bisync(
+        id1: i32,
+        report1: &SyncReport,
+        id2: i32,
+        report2: &SyncReport,
+        path: impl AsRef<Path>,
+    ) {
+        BannerOut::check(format!(
+            "Bisync Done : replica-{} <-> replica-{}, path = \"{}\", {} changes on replica-{}, {} changes on replica-{}",
+            id1,
+            id2,
+            path.as_ref().display(),
+            report1.changes(),
+            id1,
+            report2.changes(),
+            id2
+        ));
+    }
+
+    // the first pass did not finish, so the second one would carry an incomplete state back
+    pub fn bisync_halted(id1: i32, id2: i32, path: impl AsRef<Path>) {
+        BannerOut::warn(format!(
+            "Bisync Stopped : replica-{} <-> replica-{}, path = \"{}\", replica-{} is not synced back, the rest is left for the next bisync",
+            id1,
+            id2,
+            path.as_ref().display(),
+            id1
+        ));
+    }
+
+    pub fn sync_all(convergence: &Convergence, path: impl AsRef<Path>) {
+        for (id, entry) in &convergence.unresolved {
+            println!(
+                "   replica-{:<4}\"{}\" ({})",
+                id, entry.path_rel, entry.detail
+            );
+        }
+        let ids = |ids: &[i32]| {
+            ids.iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let msg = format!(
+            "Sync All : replicas {} through replica-{}, path = \"{}\", {} changes, {} unresolved",
+            ids(&convergence.synced),
+            convergence.hub,
+            path.as_ref().display(),
+            convergence.changes,
+            convergence.unresolved.len()
+        );
+        if convergence.unresolved.is_empty() && convergence.left_out.is_empty() {
+            BannerOut::check(msg);
+        } else if convergence.left_out.is_empty() {
+            BannerOut::warn(msg);
+        } else {
+            BannerOut::warn(format!(
+                "{}, replicas {} are left out",
+                msg,
+                ids(&convergence.left_out)
+            ));
+        }
+    }
+
+    // quiet unless something is synced
+    pub fn anti_entropy(link: &Link, report: &SyncReport) {
+        if report.changes() == 0 {
+            return;
+        }
+        BannerOut::new_sync(format!(
+            "Anti-Entropy : replica-{} -> replica-{}, path = \"{}\", {} changes, {} conflicts",
+            link.from,
+            link.to,
+            link.path,
+            report.changes(),
+            report.count(SyncAction::Conflict)
+        ));
+    }
+
+    pub fn anti_entropy_failed(link: &Link, err: impl std::fmt::Display, retry_in: Duration) {
+        BannerOut::warn(format!(
+            "Anti-Entropy : replica-{} -> replica-{}, path = \"{}\" failed, retry in {}s : {}",
+            link.from,
+            link.to,
+            link.path,
+            retry_in.as_secs(),
+            err
+        ));
+    }
+
     pub fn skip_both_deleted(path: &PathLocal) {
         BannerOut::check(format!("Sync Skip : \"{}\" (both deleted)", path.display()));
     }
@@ -94,6 +343,10 @@ impl SyncBanner {
         BannerOut::check(format!("Sync Skip : \"{}\" (newer)", path.display()));
     }
 
+    pub fn skip_same_content(path: &PathLocal) {
+        BannerOut::check(format!("Sync Skip : \"{}\" (same content)", path.display()));
+    }
+
     pub fn skip_from_independent_empty(path: &PathLocal) {
         BannerOut::check(format!(
             "Sync Skip : \"{}\" (from independent empty)",
@@ -101,13 +354,29 @@ impl SyncBanner {
         ));
     }
 
-    pub fn skip_different_type(path: &PathLocal) {
+    pub fn skip_unheld(path: &PathLocal) {
         BannerOut::check(format!(
-            "Sync Skip : \"{}\" (different type)",
+            "Sync Skip : \"{}\" (not held by the remote)",
             path.display()
         ));
     }
 
+    pub fn aborted(path: &PathLocal, err: impl std::fmt::Display) {
+        BannerOut::warn(format!(
+            "Sync Aborted : \"{}\", left for the next sync : {}",
+            path.display(),
+            err
+        ));
+    }
+
+    pub fn replace_type(path: &PathLocal, remote_ty: &str) {
+        BannerOut::check(format!(
+            "Sync Replace : \"{}\" by the remote {}",
+            path.display(),
+            remote_ty
+        ));
+    }
+
     pub fn delete(path: &PathLocal) {
         BannerOut::check(format!("Sync Deletion : \"{}\"", path.display()));
     }
@@ -130,7 +399,154 @@ impl SyncBanner {
         BannerOut::check(format!("Sync Overwrite : \"{}\"", path.display()));
     }
 
+    pub fn auto_resolve(path: &PathLocal, resolution: &str) {
+        BannerOut::resolve(format!(
+            "Sync Conflict : \"{}\" resolved by the config, {}",
+            path.display(),
+            resolution
+        ));
+    }
+
     pub fn conflict(path: &PathLocal) {
         BannerOut::resolve(format!("Sync Conflict : \"{}\"", path.display()));
     }
 }
+
+impl StatusBanner {
+    pub fn serving(id: i32, addr: &ServeAddr) {
+        BannerOut::check(format!("replica-{} : serving on {}", id, addr));
+    }
+
+    pub fn standalone(id: i32, centra_addr: &ServeAddr, err: impl std::fmt::Display) {
+        BannerOut::warn(format!(
+            "replica-{} : the centra at {} is unreachable ({}), running standalone",
+            id, centra_addr, err
+        ));
+    }
+
+    pub fn watches(status: &StatusRes) {
+        BannerOut::check(format!(
+            "replica-{} : {} active watches, {} unwatched directories",
+            status.id, status.active_watches, status.unwatched_dirs
+        ));
+        let named: Vec<&String> = status.roots.iter().filter(|r| !r.is_empty()).collect();
+        if !named.is_empty() {
+            BannerOut::check(format!("replica-{} : sync roots {:?}", status.id, named));
+        }
+        for session in &status.sessions {
+            BannerOut::check(format!("replica-{} : syncing {}", status.id, session));
+        }
+        if status.unwatched_dirs > 0 {
+            BannerOut::warn(format!(
+                "replica-{} is polling {} directories, consider raising fs.inotify.max_user_watches",
+                status.id, status.unwatched_dirs
+            ));
+        }
+    }
+}
+
+impl RegistryBanner {
+    pub fn register(id: i32, addr: &ServeAddr) {
+        BannerOut::check(format!("replica-{} : registered at {}", id, addr));
+    }
+
+    pub fn online(id: i32, addr: &ServeAddr) {
+        BannerOut::check(format!("replica-{} : back online at {}", id, addr));
+    }
+
+    pub fn offline(id: i32, addr: &ServeAddr, last_seen: Duration) {
+        BannerOut::warn(format!(
+            "replica-{} : offline at {}, last seen {}s ago",
+            id,
+            addr,
+            last_seen.as_secs()
+        ));
+    }
+
+    pub fn missing(online: usize, expected: usize) {
+        BannerOut::warn(format!(
+            "only {} of {} replicas are online, the others can register later",
+            online, expected
+        ));
+    }
+
+    pub fn health(health: &ReplicaHealth) {
+        match (&health.addr, health.last_seen) {
+            (Some(addr), Some(last_seen)) if health.online => BannerOut::check(format!(
+                "replica-{} : online at {}, last seen {}s ago",
+                health.id,
+                addr,
+                last_seen.as_secs()
+            )),
+            (Some(addr), Some(last_seen)) => BannerOut::cross(format!(
+                "replica-{} : offline at {}, last seen {}s ago",
+                health.id,
+                addr,
+                last_seen.as_secs()
+            )),
+            _ => BannerOut::cross(format!("replica-{} : not registered", health.id)),
+        }
+    }
+}
+
+impl NotifyBanner {
+    pub fn subscribe_request(from: i32, to: i32, path: &str) {
+        BannerOut::check(format!(
+            "Subscribed : replica-{} pulls the changes of replica-{} under \"{}\"",
+            to, from, path
+        ));
+    }
+
+    pub fn subscribed(subscriber: &Subscriber) {
+        BannerOut::check(format!(
+            "Notify : replica-{}({}) subscribed to \"{}\"",
+            subscriber.id,
+            subscriber.addr,
+            sync_path(&subscriber.root, &subscriber.path_rel)
+        ));
+    }
+
+    pub fn dropped(subscriber: &Subscriber, err: impl std::fmt::Display) {
+        BannerOut::warn(format!(
+            "Notify : replica-{}({}) is dropped from the subscribers : {}",
+            subscriber.id, subscriber.addr, err
+        ));
+    }
+
+    pub fn pull(id: i32, from: &ServeAddr, root: &str, path_rel: &str) {
+        BannerOut::new_sync(format!(
+            "Notify : \"{}\" changed on replica-{}({}), pulling",
+            sync_path(root, path_rel),
+            id,
+            from
+        ));
+    }
+
+    pub fn pulled(id: i32, root: &str, path_rel: &str, report: &SyncReport) {
+        BannerOut::check(format!(
+            "Notify Pull : \"{}\" from replica-{}, {} changes, {} conflicts",
+            sync_path(root, path_rel),
+            id,
+            report.changes(),
+            report.count(SyncAction::Conflict)
+        ));
+    }
+
+    pub fn pull_failed(id: i32, root: &str, path_rel: &str, err: impl std::fmt::Display) {
+        BannerOut::cross(format!(
+            "Notify Pull : \"{}\" from replica-{} failed : {}",
+            sync_path(root, path_rel),
+            id,
+            err
+        ));
+    }
+}
+
+// "[root:]<path>" as typed in the command line
+pub fn sync_path(root: &str, path_rel: &str) -> String {
+    let path_rel = if path_rel.is_empty() { "." } else { path_rel };
+    match root {
+        "" => path_rel.to_string(),
+        root => format!("{}:{}", root, path_rel),
+    }
+}
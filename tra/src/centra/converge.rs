@@ -0,0 +1,126 @@
+use crate::{
+    banner::SyncBanner,
+    config::RpcChannel,
+    error::{TraError, TraResult},
+    machine::{channel_connect, ServeAddr},
+    reptra::{request_sync, RsyncClient, SyncAction, SyncEntry, SyncParams, SyncReport},
+};
+
+use super::Centra;
+
+// what a `sync-all` has done to the cluster
+pub struct Convergence {
+    pub hub: i32,
+    pub synced: Vec<i32>,
+    // configured or registered, but not online
+    pub left_out: Vec<i32>,
+    pub changes: usize,
+    // the conflicts left by hand and the files changed during the sync,
+    // with the replica keeping them
+    pub unresolved: Vec<(i32, SyncEntry)>,
+}
+
+impl Convergence {
+    fn add(&mut self, id: i32, report: SyncReport) {
+        self.changes += report.changes();
+        let unresolved = report.entries.into_iter().filter(|entry| {
+            let action = SyncAction::from_i32(entry.action);
+            (action == Some(SyncAction::Conflict) && entry.detail == "manual, aborted")
+                || action == Some(SyncAction::Aborted)
+        });
+        self.unresolved.extend(unresolved.map(|entry| (id, entry)));
+    }
+}
+
+impl Centra {
+    // a star through the hub : the hub pulls from every other replica and resolves
+    // the conflicts once, then its sync times cover all of them, so every other
+    // replica pulls the same content back from the hub without conflicts
+    // all the pulls run in one session, a stopped one ends the whole round
+    pub async fn sync_all(
+        &self,
+        root: &str,
+        path_rel: &str,
+        path: &str,
+        session: &str,
+    ) -> TraResult<Convergence> {
+        let mut online = Vec::new();
+        let mut left_out = Vec::new();
+        for health in self.registry.health().await {
+            match health.addr {
+                Some(addr) if health.online => online.push((health.id, addr)),
+                _ => left_out.push(health.id),
+            }
+        }
+        if online.len() < 2 {
+            return Err(TraError::precondition(
+                "Sync All",
+                format!("{} replica online, nothing to converge", online.len()),
+            ));
+        }
+
+        let (hub, hub_addr) = online[0].clone();
+        let spokes = &online[1..];
+        let mut convergence = Convergence {
+            hub,
+            synced: online.iter().map(|(id, _)| *id).collect(),
+            left_out,
+            changes: 0,
+            unresolved: Vec::new(),
+        };
+
+        let mut hub_client = RsyncClient::new(channel_connect(&hub_addr).await?);
+        for (id, addr) in spokes {
+            SyncBanner::sync_request(*id, addr, hub, &hub_addr, path);
+            let report = pull_from(&mut hub_client, addr, root, path_rel, session).await?;
+            SyncBanner::report(&report);
+            stopped(&report)?;
+            convergence.add(hub, report);
+        }
+
+        for (id, addr) in spokes {
+            let mut client = RsyncClient::new(channel_connect(addr).await?);
+            SyncBanner::sync_request(hub, &hub_addr, *id, addr, path);
+            let report = pull_from(&mut client, &hub_addr, root, path_rel, session).await?;
+            SyncBanner::report(&report);
+            stopped(&report)?;
+            convergence.add(*id, report);
+        }
+
+        Ok(convergence)
+    }
+}
+
+async fn pull_from(
+    client: &mut RsyncClient<RpcChannel>,
+    from: &ServeAddr,
+    root: &str,
+    path_rel: &str,
+    session: &str,
+) -> TraResult<SyncReport> {
+    let params = SyncParams {
+        session: session.to_string(),
+        ..Default::default()
+    };
+    request_sync(
+        client,
+        from,
+        root.to_string(),
+        path_rel.to_string(),
+        &params,
+    )
+    .await
+}
+
+fn stopped(report: &SyncReport) -> TraResult<()> {
+    match report.stopped.is_empty() {
+        true => Ok(()),
+        false => Err(TraError::aborted(
+            "Sync All",
+            format!(
+                "session {} {}, the rest is left for the next sync-all",
+                report.session, report.stopped
+            ),
+        )),
+    }
+}
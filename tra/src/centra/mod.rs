@@ -1,22 +1,20 @@
+pub mod converge;
 pub mod greeter;
 pub mod port_collect;
+pub mod registry;
+pub mod scheduler;
 
 pub mod controller {
     #![allow(non_snake_case)]
     include!("../protos/controller.rs");
 }
 
-use std::collections::HashMap;
+use std::time::Instant;
 
-use crate::{
-    banner::BannerOut,
-    config::{MpscReceiver, MpscSender, ServiceHandle},
-    machine::ServeAddr,
-};
-use tokio::sync::mpsc;
+use crate::{banner::RegistryBanner, config::ServiceHandle, error::TraResult, machine::ServeAddr};
 use tonic::transport::Server;
 
-use crate::config::CHANNEL_BUFFER_SIZE;
+use crate::config::config;
 
 pub use controller::{
     greeter_client::GreeterClient,
@@ -26,25 +24,22 @@ pub use controller::{
     HelloReply, HelloRequest, Null, PortNumber,
 };
 
+pub use converge::Convergence;
 pub use greeter::MyGreeter;
 pub use port_collect::PortCollector;
+pub use registry::{Registry, ReplicaHealth};
 
 pub struct Centra {
     pub serve_addr: ServeAddr,
-    pub addr_tx: MpscSender<(i32, ServeAddr)>,
-    pub addr_rx: MpscReceiver<(i32, ServeAddr)>,
-    pub id_map: HashMap<i32, ServeAddr>,
+    pub registry: Registry,
     pub service_handle: Option<ServiceHandle>,
 }
 
 impl Centra {
     pub fn new(serve_addr: &ServeAddr) -> Self {
-        let (tx, rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
         Self {
             serve_addr: serve_addr.clone(),
-            addr_tx: tx,
-            addr_rx: rx,
-            id_map: HashMap::new(),
+            registry: Registry::default(),
             service_handle: None,
         }
     }
@@ -52,14 +47,18 @@ impl Centra {
     pub async fn start_services(&mut self) {
         let greeter = MyGreeter::default();
         let port_collector = PortCollector {
-            tx: self.addr_tx.clone(),
+            registry: self.registry.clone(),
         };
 
         let server = Server::builder()
             .add_service(GreeterServer::new(greeter))
             .add_service(PortCollectServer::new(port_collector))
             // .serve_with_shutdown("[::]:8080".parse().unwrap(), ctrl_c_singal());
-            .serve(self.serve_addr.addr().parse().unwrap());
+            .serve(
+                self.serve_addr
+                    .socket_addr()
+                    .expect("invalid centra address"),
+            );
 
         // boot the tra server here
         self.service_handle = Some(tokio::spawn(async {
@@ -68,24 +67,29 @@ impl Centra {
             println!("Shutting down the tra server...");
             res
         }));
+
+        let registry = self.registry.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(config().heartbeat_interval).await;
+                registry.sweep().await;
+            }
+        });
     }
 
-    pub async fn collect_ports(&mut self, rep_num: usize) {
-        for _ in 0..rep_num {
-            if let Some((id, serve_addr)) = self.addr_rx.recv().await {
-                self.id_map.insert(id, serve_addr);
-                BannerOut::check(format!(
-                    "id : {}, port : {} collected",
-                    id,
-                    serve_addr.port()
-                ));
-            } else {
-                panic!("The port collect channel is closed unexpectedly.");
+    // wait for the spawned replicas before the prompt, the late ones still register later
+    pub async fn wait_replicas(&self, rep_num: usize) {
+        let start = Instant::now();
+        while self.registry.online_count().await < rep_num {
+            if start.elapsed() > config().offline_after {
+                RegistryBanner::missing(self.registry.online_count().await, rep_num);
+                return;
             }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
         }
     }
 
-    pub fn get_addr(&self, id: i32) -> ServeAddr {
-        self.id_map[&id].clone()
+    pub async fn get_addr(&self, id: i32) -> TraResult<ServeAddr> {
+        self.registry.get_addr(id).await
     }
 }
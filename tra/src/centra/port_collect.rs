@@ -1,9 +1,8 @@
-use tokio::sync::mpsc::Sender;
 use tonic::{Request, Response, Status};
 
 use crate::machine::ServeAddr;
 
-use super::{Null, PortCollect, PortNumber};
+use super::{Null, PortCollect, PortNumber, Registry};
 
 pub mod controller {
     #![allow(non_snake_case)]
@@ -11,18 +10,27 @@ pub mod controller {
 }
 
 pub struct PortCollector {
-    pub tx: Sender<(i32, ServeAddr)>,
+    pub registry: Registry,
 }
 
 #[tonic::async_trait]
 impl PortCollect for PortCollector {
     async fn send_port(&self, req: Request<PortNumber>) -> Result<Response<Null>, Status> {
-        let inner = req.into_inner();
-        let serve_addr = ServeAddr::new(inner.port as u16);
-        self.tx
-            .send((inner.id, serve_addr))
-            .await
-            .expect("failed to send port");
+        let (id, serve_addr) = sender_addr(req)?;
+        self.registry.register(id, serve_addr).await;
         Ok(Response::new(Null {}))
     }
+
+    async fn heartbeat(&self, req: Request<PortNumber>) -> Result<Response<Null>, Status> {
+        let (id, serve_addr) = sender_addr(req)?;
+        self.registry.heartbeat(id, serve_addr).await;
+        Ok(Response::new(Null {}))
+    }
+}
+
+fn sender_addr(req: Request<PortNumber>) -> Result<(i32, ServeAddr), Status> {
+    let remote = req.remote_addr();
+    let inner = req.into_inner();
+    let serve_addr = ServeAddr::from_sender(&inner.host, inner.port as u16, remote)?;
+    Ok((inner.id, serve_addr))
 }
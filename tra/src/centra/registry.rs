@@ -0,0 +1,123 @@
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::RwLock;
+
+use crate::{
+    banner::RegistryBanner,
+    config::config,
+    error::{TraError, TraResult},
+    machine::ServeAddr,
+};
+
+pub struct ReplicaEntry {
+    pub addr: ServeAddr,
+    pub last_seen: Instant,
+    // flipped by the sweep and the heartbeats, so that each change is reported once
+    pub online: bool,
+}
+
+pub struct ReplicaHealth {
+    pub id: i32,
+    // None if never registered
+    pub addr: Option<ServeAddr>,
+    pub online: bool,
+    pub last_seen: Option<Duration>,
+}
+
+// the replicas known by the centra, shared with the port collect service
+#[derive(Default, Clone)]
+pub struct Registry {
+    entries: Arc<RwLock<BTreeMap<i32, ReplicaEntry>>>,
+}
+
+impl Registry {
+    // a new replica, or a restarted one with a new address
+    pub async fn register(&self, id: i32, addr: ServeAddr) {
+        RegistryBanner::register(id, &addr);
+        let entry = ReplicaEntry {
+            addr,
+            last_seen: Instant::now(),
+            online: true,
+        };
+        self.entries.write().await.insert(id, entry);
+    }
+
+    pub async fn heartbeat(&self, id: i32, addr: ServeAddr) {
+        let mut entries = self.entries.write().await;
+        match entries.get_mut(&id) {
+            Some(entry) if entry.addr == addr => {
+                entry.last_seen = Instant::now();
+                if !entry.online {
+                    entry.online = true;
+                    RegistryBanner::online(id, &addr);
+                }
+            }
+            // the centra has restarted, or the replica has moved
+            _ => {
+                drop(entries);
+                self.register(id, addr).await;
+            }
+        }
+    }
+
+    // mark the replicas without recent heartbeats offline
+    pub async fn sweep(&self) {
+        let mut entries = self.entries.write().await;
+        for (id, entry) in entries.iter_mut() {
+            if entry.online && entry.last_seen.elapsed() > config().offline_after {
+                entry.online = false;
+                RegistryBanner::offline(*id, &entry.addr, entry.last_seen.elapsed());
+            }
+        }
+    }
+
+    pub async fn get_addr(&self, id: i32) -> TraResult<ServeAddr> {
+        match self.entries.read().await.get(&id) {
+            None => Err(TraError::not_found(
+                "Registry",
+                format!("replica-{} is not registered", id),
+            )),
+            Some(entry) if !entry.online => Err(TraError::unavailable(
+                "Registry",
+                format!(
+                    "replica-{} is offline, last seen {}s ago",
+                    id,
+                    entry.last_seen.elapsed().as_secs()
+                ),
+            )),
+            Some(entry) => Ok(entry.addr.clone()),
+        }
+    }
+
+    pub async fn online_count(&self) -> usize {
+        let entries = self.entries.read().await;
+        entries.values().filter(|entry| entry.online).count()
+    }
+
+    // the configured replicas, and the registered daemons outside the config
+    pub async fn health(&self) -> Vec<ReplicaHealth> {
+        let entries = self.entries.read().await;
+        let mut ids: Vec<i32> = (1..=config().replicas as i32).collect();
+        ids.extend(entries.keys().filter(|id| !config().contains(**id)));
+        ids.into_iter()
+            .map(|id| match entries.get(&id) {
+                Some(entry) => ReplicaHealth {
+                    id,
+                    addr: Some(entry.addr.clone()),
+                    online: entry.online,
+                    last_seen: Some(entry.last_seen.elapsed()),
+                },
+                None => ReplicaHealth {
+                    id,
+                    addr: None,
+                    online: false,
+                    last_seen: None,
+                },
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use tonic::Request;
+
+use crate::{
+    banner::SyncBanner,
+    config::{config, Link},
+    error::TraResult,
+    machine::{channel_connect, retry_rpc},
+    reptra::{request_sync, QueryReq, RsyncClient, SyncAction, SyncParams, SyncReport},
+};
+
+use super::{Centra, Registry};
+
+// what a link remembers between the rounds
+struct LinkState {
+    // the mod time of the source after the last successful pull
+    last_mod: Option<HashMap<i32, i32>>,
+    failures: u32,
+    retry_at: Instant,
+}
+
+impl Centra {
+    // pull every configured link once per round, so that the replicas converge
+    // without anyone typing `sync`
+    pub fn start_scheduler(&self) {
+        let anti_entropy = &config().anti_entropy;
+        if anti_entropy.links.is_empty() {
+            return;
+        }
+        let registry = self.registry.clone();
+        tokio::spawn(async move {
+            let mut states: Vec<LinkState> = anti_entropy
+                .links
+                .iter()
+                .map(|_| LinkState {
+                    last_mod: None,
+                    failures: 0,
+                    retry_at: Instant::now(),
+                })
+                .collect();
+            loop {
+                tokio::time::sleep(anti_entropy.interval + jitter(anti_entropy.jitter)).await;
+                for (link, state) in anti_entropy.links.iter().zip(states.iter_mut()) {
+                    if state.retry_at > Instant::now() {
+                        continue;
+                    }
+                    match pull_link(&registry, link, state).await {
+                        Ok(Some(report)) => {
+                            state.failures = 0;
+                            SyncBanner::anti_entropy(link, &report);
+                        }
+                        // nothing changed on the source since the last pull
+                        Ok(None) => state.failures = 0,
+                        Err(e) => {
+                            state.failures += 1;
+                            let backoff = backoff(state.failures);
+                            state.retry_at = Instant::now() + backoff;
+                            SyncBanner::anti_entropy_failed(link, &e, backoff);
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+async fn pull_link(
+    registry: &Registry,
+    link: &Link,
+    state: &mut LinkState,
+) -> TraResult<Option<SyncReport>> {
+    let from = registry.get_addr(link.from).await?;
+    let to = registry.get_addr(link.to).await?;
+
+    let from_client = RsyncClient::new(channel_connect(&from).await?);
+    let source = retry_rpc("Query", || {
+        let mut client = from_client.clone();
+        let request = Request::new(QueryReq {
+            root: link.root.clone(),
+            path_rel: link.path_rel.clone(),
+            hash: false,
+        });
+        async move { client.query(request).await }
+    })
+    .await?
+    .into_inner();
+    if state.last_mod.as_ref() == Some(&source.mod_time) {
+        return Ok(None);
+    }
+
+    let mut to_client = RsyncClient::new(channel_connect(&to).await?);
+    let report = request_sync(
+        &mut to_client,
+        &from,
+        link.root.clone(),
+        link.path_rel.clone(),
+        &SyncParams {
+            unattended: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+    // the aborted files and a stopped sync are pulled again in the next round
+    if report.count(SyncAction::Aborted) == 0 && report.stopped.is_empty() {
+        state.last_mod = Some(source.mod_time);
+    }
+    Ok(Some(report))
+}
+
+fn jitter(max: Duration) -> Duration {
+    let millis = rand::thread_rng().gen_range(0..=max.as_millis() as u64);
+    Duration::from_millis(millis)
+}
+
+// the interval doubled on each failure in a row
+fn backoff(failures: u32) -> Duration {
+    let anti_entropy = &config().anti_entropy;
+    let factor = 2u32.saturating_pow(failures.min(16));
+    anti_entropy
+        .interval
+        .saturating_mul(factor)
+        .min(anti_entropy.max_backoff)
+}
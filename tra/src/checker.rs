@@ -1,3 +1,6 @@
+use std::path::{Component, Path, PathBuf};
+
+use lazy_static::lazy_static;
 use regex::Regex;
 
 // 1. start with /
@@ -5,11 +8,100 @@ use regex::Regex;
 // 3. only contains [a-zA-Z0-9_.-]
 // 4. end with / or nothing
 #[allow(dead_code)]
-const LEAGL_RULE_ABSOLUTE: &'static str = r#"^(/([a-zA-Z0-9_.-]+))+/?$"#;
+const LEAGL_RULE_ABSOLUTE: &str = r#"^(/([a-zA-Z0-9_.-]+))+/?$"#;
 #[allow(dead_code)]
-const LEGAL_RULE_RELATIVE: &'static str = r#"^([a-zA-Z0-9_.-]+/)*([a-zA-Z0-9_.-]+)?$"#;
+const LEGAL_RULE_RELATIVE: &str = r#"^([a-zA-Z0-9_.-]+/)*([a-zA-Z0-9_.-]+)?$"#;
+
+const LEGAL_RULE_ROOT_NAME: &str = r#"^[a-zA-Z0-9_.-]+$"#;
+
+lazy_static! {
+    static ref LEGAL_RELATIVE: Regex = Regex::new(LEGAL_RULE_RELATIVE).unwrap();
+    static ref LEGAL_ROOT_NAME: Regex = Regex::new(LEGAL_RULE_ROOT_NAME).unwrap();
+}
 
 pub fn check_legal(path_str: &String) -> bool {
-    let regex = Regex::new(LEGAL_RULE_RELATIVE).unwrap();
-    regex.is_match(path_str)
+    LEGAL_RELATIVE.is_match(path_str)
+}
+
+pub fn check_root_name(name: &str) -> bool {
+    LEGAL_ROOT_NAME.is_match(name)
+}
+
+// "<root>:<path>" for a named sync root, "<path>" for the default one
+pub fn parse_sync_path(arg: &str) -> Option<(String, String)> {
+    let (root, path_rel) = match arg.split_once(':') {
+        Some((root, path)) => (root.to_string(), path.to_string()),
+        None => (String::new(), arg.to_string()),
+    };
+    if !check_legal(&path_rel) || !(root.is_empty() || check_root_name(&root)) {
+        return None;
+    }
+    Some((root, path_rel))
+}
+
+// the relative path without "." and the trailing "/", "" for the root
+pub fn normal_path(path: &str) -> PathBuf {
+    Path::new(path)
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+// the ignore rules of a replica, in glob patterns
+// 1. a pattern without / matches the name of any file or dir, like "*.swp"
+// 2. a pattern with / matches the path relative to the root, like "build/cache"
+// 3. everything under an ignored dir is ignored
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    names: Vec<Regex>,
+    paths: Vec<Regex>,
+}
+
+impl IgnoreRules {
+    pub fn new(patterns: &[String]) -> Result<Self, String> {
+        let mut rules = Self::default();
+        for pattern in patterns {
+            let trimmed = pattern.trim_matches('/');
+            if trimmed.is_empty() {
+                return Err(format!("empty pattern \"{}\"", pattern));
+            }
+            let regex = Regex::new(&glob_to_regex(trimmed))
+                .map_err(|e| format!("invalid pattern \"{}\" : {}", pattern, e))?;
+            if trimmed.contains('/') {
+                rules.paths.push(regex);
+            } else {
+                rules.names.push(regex);
+            }
+        }
+        Ok(rules)
+    }
+
+    pub fn is_ignored(&self, path_rel: &str) -> bool {
+        let mut prefix = String::new();
+        for name in path_rel.split('/').filter(|name| !name.is_empty()) {
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(name);
+            if self.names.iter().any(|r| r.is_match(name))
+                || self.paths.iter().any(|r| r.is_match(&prefix))
+            {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
 }
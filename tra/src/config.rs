@@ -1,39 +1,601 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
 use fast_rsync::SignatureOptions;
-use lazy_static::lazy_static;
+use serde::Deserialize;
 use tokio::signal;
 
-fn get_tmp_path() -> String {
-    let mut path_abs = std::env::current_dir().unwrap();
-    path_abs.push("tmp/");
-    path_abs.to_str().unwrap().to_string()
+use crate::{
+    checker::{check_root_name, normal_path, parse_sync_path, IgnoreRules},
+    conflicts::ConflictPolicy,
+    error::{TraError, TraResult},
+    replica::node::root_display,
+};
+
+// the config file used when no `--config` is given, optional
+pub const DEFAULT_CONFIG_FILE: &str = "tra.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub replicas: usize,
+    pub host: String,
+    pub port: u16,
+    pub tmp_path: PathBuf,
+    pub channel_buffer_size: usize,
+    pub poll_interval: u64,
+    pub heartbeat_interval: u64,
+    pub offline_after: u64,
+    pub notify_quiet_ms: u64,
+    pub sync_timeout: u64,
+    pub signature: SignatureSection,
+    pub retry: RetrySection,
+    pub anti_entropy: AntiEntropySection,
+    pub replica: HashMap<String, ReplicaSection>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SignatureSection {
+    pub block_size: u32,
+    pub crypto_hash_size: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetrySection {
+    pub attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub connect_timeout: u64,
+    pub rpc_timeout: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AntiEntropySection {
+    pub interval: u64,
+    pub jitter: u64,
+    pub max_backoff: u64,
+    pub links: Vec<LinkSection>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LinkSection {
+    pub from: i32,
+    pub to: i32,
+    pub path: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplicaSection {
+    pub root: Option<PathBuf>,
+    // the named sync roots besides the default one
+    pub roots: BTreeMap<String, PathBuf>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    // the host told to the others, when it differs from the bound one
+    pub advertise: Option<String>,
+    pub ignore: Vec<String>,
+    // "[root:]<path>", the only subtrees held by a partial replica
+    pub partial: Vec<String>,
+    pub conflict: ConflictPolicy,
+}
+
+// the command line options which override the config file
+#[derive(Debug, Default)]
+pub struct Overrides {
+    pub config: Option<PathBuf>,
+    pub replicas: Option<usize>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
 }
 
-lazy_static! {
-    pub static ref TMP_PATH: String = get_tmp_path();
+#[derive(Debug)]
+pub struct Config {
+    pub replicas: usize,
+    pub host: String,
+    pub port: u16,
+    pub tmp_path: PathBuf,
+    pub channel_buffer_size: usize,
+    pub poll_interval: Duration,
+    pub heartbeat_interval: Duration,
+    // a replica without heartbeats for this long is offline
+    pub offline_after: Duration,
+    // the changes are announced to the subscribers after no change for this long
+    pub notify_quiet: Duration,
+    // a sync stops at the next file after this long, None for no deadline
+    pub sync_timeout: Option<Duration>,
+    pub sig_option: SignatureOptions,
+    pub retry: RetryConfig,
+    pub anti_entropy: AntiEntropyConfig,
+    pub replica: HashMap<i32, ReplicaConfig>,
 }
 
-pub const BASE_REP_NUM: usize = 3;
+// the connections and the idempotent rpcs are retried with exponential backoff
+#[derive(Debug, Clone)]
+pub struct RetryConfig {
+    pub attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub connect_timeout: Duration,
+    // the deadline of each attempt
+    pub rpc_timeout: Duration,
+}
 
-pub const CHANNEL_BUFFER_SIZE: usize = 1024;
+// the links synced by the centra periodically, disabled if there is no link
+#[derive(Debug, Clone)]
+pub struct AntiEntropyConfig {
+    pub interval: Duration,
+    // a random delay up to this is added to each interval
+    pub jitter: Duration,
+    // a failing link waits the interval doubled on each failure, up to this
+    pub max_backoff: Duration,
+    pub links: Vec<Link>,
+}
+
+// `to` pulls the path from `from`
+#[derive(Debug, Clone)]
+pub struct Link {
+    pub from: i32,
+    pub to: i32,
+    // as written in the config, "[root:]<path>"
+    pub path: String,
+    pub root: String,
+    pub path_rel: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplicaConfig {
+    pub id: i32,
+    pub root: PathBuf,
+    pub roots: BTreeMap<String, PathBuf>,
+    pub host: String,
+    // random port if not given
+    pub port: Option<u16>,
+    pub advertise: Option<String>,
+    pub ignore: IgnoreRules,
+    // the held subtrees of each partial root, the other roots are held entirely
+    pub partial: BTreeMap<String, Vec<PathBuf>>,
+    pub conflict: ConflictPolicy,
+}
+
+impl Default for ConfigFile {
+    fn default() -> Self {
+        Self {
+            replicas: 3,
+            // loopback only, set "0.0.0.0" or "::" to accept other machines
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+            tmp_path: PathBuf::from("tmp"),
+            channel_buffer_size: 1024,
+            poll_interval: 2,
+            heartbeat_interval: 2,
+            offline_after: 6,
+            notify_quiet_ms: 500,
+            sync_timeout: 0,
+            signature: SignatureSection::default(),
+            retry: RetrySection::default(),
+            anti_entropy: AntiEntropySection::default(),
+            replica: HashMap::new(),
+        }
+    }
+}
+
+impl Default for SignatureSection {
+    fn default() -> Self {
+        Self {
+            block_size: 1024,
+            crypto_hash_size: 16,
+        }
+    }
+}
+
+impl Default for RetrySection {
+    fn default() -> Self {
+        Self {
+            attempts: 4,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 2000,
+            connect_timeout: 3,
+            rpc_timeout: 30,
+        }
+    }
+}
+
+impl Default for AntiEntropySection {
+    fn default() -> Self {
+        Self {
+            interval: 60,
+            jitter: 10,
+            max_backoff: 600,
+            links: Vec::new(),
+        }
+    }
+}
+
+impl Overrides {
+    // take out the `--option value` pairs, leave the other args in order
+    // the unknown flags after the command belong to it, e.g. `sync --dry-run`
+    pub fn parse(args: &mut Vec<String>) -> TraResult<Self> {
+        const OPTIONS: [&str; 4] = ["--config", "--replicas", "--host", "--port"];
+        let mut overrides = Self::default();
+        let mut rest = Vec::new();
+        let mut iter = args.drain(..);
+        while let Some(arg) = iter.next() {
+            let command_flag = rest.len() > 1 && !OPTIONS.contains(&arg.as_str());
+            if !arg.starts_with("--") || command_flag {
+                rest.push(arg);
+                continue;
+            }
+            let value = iter
+                .next()
+                .ok_or_else(|| invalid_option(format!("missing value for option \"{}\"", arg)))?;
+            let invalid = || {
+                invalid_option(format!(
+                    "invalid value \"{}\" for option \"{}\"",
+                    value, arg
+                ))
+            };
+            match arg.as_str() {
+                "--config" => overrides.config = Some(PathBuf::from(&value)),
+                "--replicas" => overrides.replicas = Some(value.parse().map_err(|_| invalid())?),
+                "--host" => overrides.host = Some(value.clone()),
+                "--port" => overrides.port = Some(value.parse().map_err(|_| invalid())?),
+                _ => return Err(invalid_option(format!("unknown option \"{}\"", arg))),
+            }
+        }
+        drop(iter);
+        *args = rest;
+        Ok(overrides)
+    }
+}
 
-pub const TRA_PORT: u16 = 8080;
-pub const TRA_STATIC_ADDR: &str = "http://[::]:8080";
+impl Config {
+    pub fn load(overrides: &Overrides) -> TraResult<Self> {
+        let (file, source) = match &overrides.config {
+            Some(path) => (read_config_file(path)?, path.display().to_string()),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => (
+                read_config_file(DEFAULT_CONFIG_FILE)?,
+                DEFAULT_CONFIG_FILE.to_string(),
+            ),
+            None => (ConfigFile::default(), "default config".to_string()),
+        };
+        Self::from_file(file, overrides).map_err(|e| e.at(source))
+    }
+
+    // the built-in defaults, whatever tra.toml is in the working directory
+    #[cfg(test)]
+    pub fn defaults() -> Self {
+        Self::from_file(ConfigFile::default(), &Overrides::default()).unwrap()
+    }
+
+    fn from_file(mut file: ConfigFile, overrides: &Overrides) -> TraResult<Self> {
+        file.replicas = overrides.replicas.unwrap_or(file.replicas);
+        file.port = overrides.port.unwrap_or(file.port);
+        if let Some(host) = &overrides.host {
+            file.host = host.clone();
+        }
+
+        if file.host.is_empty() {
+            return Err(invalid("host should not be empty"));
+        }
+        if file.replicas == 0 {
+            return Err(invalid("at least one replica should be configured"));
+        }
+        if file.channel_buffer_size == 0 {
+            return Err(invalid("channel_buffer_size should be positive"));
+        }
+        if file.poll_interval == 0 {
+            return Err(invalid("poll_interval should be positive"));
+        }
+        if file.heartbeat_interval == 0 {
+            return Err(invalid("heartbeat_interval should be positive"));
+        }
+        if file.offline_after <= file.heartbeat_interval {
+            return Err(invalid(
+                "offline_after should be longer than heartbeat_interval",
+            ));
+        }
+        if file.notify_quiet_ms == 0 {
+            return Err(invalid("notify_quiet_ms should be positive"));
+        }
+        if file.signature.block_size == 0 {
+            return Err(invalid("signature.block_size should be positive"));
+        }
+        if !(1..=16).contains(&file.signature.crypto_hash_size) {
+            return Err(invalid("signature.crypto_hash_size should be in 1..=16"));
+        }
+        if file.retry.attempts == 0 {
+            return Err(invalid("retry.attempts should be positive"));
+        }
+        if file.retry.max_backoff_ms < file.retry.initial_backoff_ms {
+            return Err(invalid(
+                "retry.max_backoff_ms should not be less than retry.initial_backoff_ms",
+            ));
+        }
+        if file.retry.connect_timeout == 0 || file.retry.rpc_timeout == 0 {
+            return Err(invalid(
+                "retry.connect_timeout and retry.rpc_timeout should be positive",
+            ));
+        }
+
+        if file.anti_entropy.interval == 0 {
+            return Err(invalid("anti_entropy.interval should be positive"));
+        }
+        if file.anti_entropy.max_backoff < file.anti_entropy.interval {
+            return Err(invalid(
+                "anti_entropy.max_backoff should not be less than anti_entropy.interval",
+            ));
+        }
+        let mut links = Vec::new();
+        for link in file.anti_entropy.links {
+            let known = |id: i32| id >= 1 && id as usize <= file.replicas;
+            if !known(link.from) || !known(link.to) || link.from == link.to {
+                return Err(invalid(format!(
+                    "anti_entropy link {} -> {} should be between two replicas in 1..={}",
+                    link.from, link.to, file.replicas
+                )));
+            }
+            let (root, path_rel) = parse_sync_path(&link.path).ok_or_else(|| {
+                invalid(format!(
+                    "anti_entropy link : illegal path \"{}\"",
+                    link.path
+                ))
+            })?;
+            links.push(Link {
+                from: link.from,
+                to: link.to,
+                path: link.path,
+                root,
+                path_rel,
+            });
+        }
+
+        let cwd = std::env::current_dir().map_err(|e| TraError::io("Config", ".", e))?;
+        let tmp_path = cwd.join(&file.tmp_path);
+
+        let mut replica = HashMap::new();
+        for (key, section) in file.replica {
+            let id: i32 = key.parse().map_err(|_| {
+                invalid(format!(
+                    "[replica.{}] should be keyed by the replica id",
+                    key
+                ))
+            })?;
+            if id < 1 || id as usize > file.replicas {
+                return Err(invalid(format!(
+                    "[replica.{}] is out of range, the replica ids are 1..={}",
+                    id, file.replicas
+                )));
+            }
+            let ignore = IgnoreRules::new(&section.ignore)
+                .map_err(|e| invalid(format!("[replica.{}] ignore : {}", id, e)))?;
+            let root = section
+                .root
+                .map_or(default_root(&tmp_path, id), |root| resolve_path(&cwd, root));
+            let mut roots = BTreeMap::new();
+            for (name, root) in section.roots {
+                if !check_root_name(&name) {
+                    return Err(invalid(format!(
+                        "[replica.{}] invalid root name \"{}\"",
+                        id, name
+                    )));
+                }
+                roots.insert(name, resolve_path(&cwd, root));
+            }
+            let mut partial: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+            for held in &section.partial {
+                let (name, path_rel) = parse_sync_path(held).ok_or_else(|| {
+                    invalid(format!(
+                        "[replica.{}] partial : illegal path \"{}\"",
+                        id, held
+                    ))
+                })?;
+                if !name.is_empty() && !roots.contains_key(&name) {
+                    return Err(invalid(format!(
+                        "[replica.{}] partial : unknown sync root \"{}\"",
+                        id, name
+                    )));
+                }
+                partial
+                    .entry(name)
+                    .or_default()
+                    .push(normal_path(&path_rel));
+            }
+            if section.host.as_deref() == Some("") || section.advertise.as_deref() == Some("") {
+                return Err(invalid(format!(
+                    "[replica.{}] host should not be empty",
+                    id
+                )));
+            }
+            let conf = ReplicaConfig {
+                id,
+                root,
+                roots,
+                host: section.host.unwrap_or(file.host.clone()),
+                port: section.port,
+                advertise: section.advertise,
+                ignore,
+                partial,
+                conflict: section.conflict,
+            };
+            replica.insert(id, conf);
+        }
+        for id in 1..=file.replicas as i32 {
+            replica
+                .entry(id)
+                .or_insert_with(|| ReplicaConfig::new(id, &tmp_path, &file.host));
+        }
+
+        let mut ids: Vec<&i32> = replica.keys().collect();
+        ids.sort();
+        for (i, id1) in ids.iter().enumerate() {
+            let conf1 = &replica[id1];
+            if conf1.port == Some(file.port) && conf1.host == file.host {
+                return Err(invalid(format!(
+                    "[replica.{}] {}:{} is used by the central server",
+                    id1, file.host, file.port
+                )));
+            }
+            for id2 in ids.iter().skip(i + 1) {
+                let conf2 = &replica[id2];
+                if conf1.port.is_some() && conf1.port == conf2.port && conf1.host == conf2.host {
+                    return Err(invalid(format!(
+                        "replica {} and {} listen on the same address {}:{}",
+                        id1,
+                        id2,
+                        conf1.host,
+                        conf1.port.unwrap()
+                    )));
+                }
+            }
+        }
+
+        // no directory is synchronized by two roots
+        let all_roots: Vec<(String, PathBuf)> = ids
+            .iter()
+            .flat_map(|id| {
+                replica[id]
+                    .all_roots()
+                    .into_iter()
+                    .map(move |(name, root)| (root_display(**id, &name), root))
+            })
+            .collect();
+        for (i, (name1, root1)) in all_roots.iter().enumerate() {
+            for (name2, root2) in all_roots.iter().skip(i + 1) {
+                if root1.starts_with(root2) || root2.starts_with(root1) {
+                    return Err(invalid(format!(
+                        "the roots of {} and {} overlap : \"{}\", \"{}\"",
+                        name1,
+                        name2,
+                        root1.display(),
+                        root2.display()
+                    )));
+                }
+            }
+        }
+
+        Ok(Self {
+            replicas: file.replicas,
+            host: file.host,
+            port: file.port,
+            tmp_path,
+            channel_buffer_size: file.channel_buffer_size,
+            poll_interval: Duration::from_secs(file.poll_interval),
+            heartbeat_interval: Duration::from_secs(file.heartbeat_interval),
+            offline_after: Duration::from_secs(file.offline_after),
+            notify_quiet: Duration::from_millis(file.notify_quiet_ms),
+            sync_timeout: match file.sync_timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            sig_option: SignatureOptions {
+                block_size: file.signature.block_size,
+                crypto_hash_size: file.signature.crypto_hash_size,
+            },
+            retry: RetryConfig {
+                attempts: file.retry.attempts,
+                initial_backoff: Duration::from_millis(file.retry.initial_backoff_ms),
+                max_backoff: Duration::from_millis(file.retry.max_backoff_ms),
+                connect_timeout: Duration::from_secs(file.retry.connect_timeout),
+                rpc_timeout: Duration::from_secs(file.retry.rpc_timeout),
+            },
+            anti_entropy: AntiEntropyConfig {
+                interval: Duration::from_secs(file.anti_entropy.interval),
+                jitter: Duration::from_secs(file.anti_entropy.jitter),
+                max_backoff: Duration::from_secs(file.anti_entropy.max_backoff),
+                links,
+            },
+            replica,
+        })
+    }
+
+    // the replica not in the config is allowed to run as a daemon, with the default settings
+    pub fn replica(&self, id: i32) -> ReplicaConfig {
+        self.replica
+            .get(&id)
+            .cloned()
+            .unwrap_or_else(|| ReplicaConfig::new(id, &self.tmp_path, &self.host))
+    }
+
+    pub fn contains(&self, id: i32) -> bool {
+        id >= 1 && id as usize <= self.replicas
+    }
+}
+
+fn read_config_file(path: impl AsRef<Path>) -> TraResult<ConfigFile> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path).map_err(|e| TraError::io("Config", path, e))?;
+    toml::from_str(&content).map_err(|e| invalid(e).at(path))
+}
+
+fn invalid(cause: impl std::fmt::Display) -> TraError {
+    TraError::invalid("Config", cause)
+}
+
+fn invalid_option(cause: String) -> TraError {
+    TraError::invalid("Options", cause)
+}
+
+impl ReplicaConfig {
+    fn new(id: i32, tmp_path: &Path, host: &str) -> Self {
+        Self {
+            id,
+            root: default_root(tmp_path, id),
+            roots: BTreeMap::new(),
+            host: host.to_string(),
+            port: None,
+            advertise: None,
+            ignore: IgnoreRules::default(),
+            partial: BTreeMap::new(),
+            conflict: ConflictPolicy::default(),
+        }
+    }
+
+    // the default root has an empty name
+    pub fn all_roots(&self) -> Vec<(String, PathBuf)> {
+        let mut roots = vec![(String::new(), self.root.clone())];
+        roots.extend(self.roots.clone());
+        roots
+    }
+}
+
+// relative to the working directory, and "~" for the home directory
+pub fn resolve_path(cwd: &Path, path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => cwd.join(path),
+    }
+}
+
+fn default_root(tmp_path: &Path, id: i32) -> PathBuf {
+    tmp_path.join(format!("replica-{}", id))
+}
+
+// set once at startup, before any replica or the centra is started
+pub fn init_config(config: Config) {
+    CONFIG.set(config).expect("config is already loaded");
+}
+
+pub fn config() -> &'static Config {
+    CONFIG.get().expect("config is not loaded")
+}
 
 pub async fn ctrl_c_singal() {
     signal::ctrl_c().await.unwrap()
 }
 
-pub const SIG_OPTION: SignatureOptions = SignatureOptions {
-    block_size: 1024,
-    crypto_hash_size: 16,
-};
-
-pub type MyResult<T> = Result<T, String>;
 pub type RpcChannel = tonic::transport::Channel;
 pub type MpscSender<T> = tokio::sync::mpsc::Sender<T>;
 pub type MpscReceiver<T> = tokio::sync::mpsc::Receiver<T>;
 pub type ServiceHandle = tokio::task::JoinHandle<Result<(), tonic::transport::Error>>;
-
-pub fn sync_folder_prefix(id: i32) -> String {
-    format!("{}replica-{}", *TMP_PATH, id)
-}
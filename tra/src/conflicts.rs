@@ -1,17 +1,32 @@
 use diff::lines;
+use serde::Deserialize;
 use std::str::from_utf8;
 use tokio::process::Command;
 
 use crate::{
     banner::BannerOut,
+    error::TraResult,
     replica::{
         meta::{get_sync_bytes, read_bytes, write_bytes},
         node::SyncOption,
         path_local::PathLocal,
     },
-    MyResult,
+    timestamp::VectorTime,
 };
 
+// how a replica resolves the conflicts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    // ask the user every time
+    #[default]
+    Prompt,
+    // always keep the local version
+    Local,
+    // always take the remote version
+    Remote,
+}
+
 pub fn format_diff(diffed: Vec<diff::Result<&str>>) -> String {
     let mut last_status = diff::Result::Both("", "");
     let mut tui = String::new();
@@ -61,9 +76,13 @@ pub fn format_diff(diffed: Vec<diff::Result<&str>>) -> String {
     tui
 }
 
-pub async fn manually_resolve(path: &PathLocal, op: SyncOption) -> MyResult<bool> {
+pub async fn manually_resolve(
+    path: &PathLocal,
+    op: SyncOption,
+    mod_time: &VectorTime,
+) -> TraResult<bool> {
     let original = read_bytes(path).await?;
-    let synced = get_sync_bytes(path, op.client).await?;
+    let (synced, _) = get_sync_bytes(path, op, mod_time).await?;
     let diffed = lines(
         from_utf8(&original.as_slice()).unwrap(),
         from_utf8(&synced.as_slice()).unwrap(),
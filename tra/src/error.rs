@@ -0,0 +1,137 @@
+use std::{fmt::Display, path::Path};
+
+use tonic::{Code, Status};
+
+pub type TraResult<T> = Result<T, TraError>;
+
+fn transport_failed(status: &Status) -> bool {
+    let mut source = std::error::Error::source(status);
+    while let Some(err) = source {
+        if err.is::<tonic::transport::Error>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    // the path, the root or the replica does not exist
+    NotFound,
+    // the peer can not be reached, or the rpc timed out
+    Unavailable,
+    // interrupted halfway, e.g. the file is changed during the sync
+    Aborted,
+    // not allowed in the current state, e.g. an ignored path
+    FailedPrecondition,
+    InvalidArgument,
+    // the local file system fails
+    Io,
+    Internal,
+}
+
+#[derive(Debug, Clone)]
+pub struct TraError {
+    pub kind: ErrorKind,
+    // what was being done, e.g. "Write Bytes"
+    pub op: &'static str,
+    pub path: Option<String>,
+    pub cause: String,
+}
+
+impl Display for TraError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{} : \"{}\" : {}", self.op, path, self.cause),
+            None => write!(f, "{} : {}", self.op, self.cause),
+        }
+    }
+}
+
+impl TraError {
+    pub fn new(kind: ErrorKind, op: &'static str, cause: impl Display) -> Self {
+        Self {
+            kind,
+            op,
+            path: None,
+            cause: cause.to_string(),
+        }
+    }
+
+    pub fn at(mut self, path: impl AsRef<Path>) -> Self {
+        self.path = Some(path.as_ref().display().to_string());
+        self
+    }
+
+    pub fn not_found(op: &'static str, cause: impl Display) -> Self {
+        Self::new(ErrorKind::NotFound, op, cause)
+    }
+
+    pub fn unavailable(op: &'static str, cause: impl Display) -> Self {
+        Self::new(ErrorKind::Unavailable, op, cause)
+    }
+
+    pub fn aborted(op: &'static str, cause: impl Display) -> Self {
+        Self::new(ErrorKind::Aborted, op, cause)
+    }
+
+    pub fn precondition(op: &'static str, cause: impl Display) -> Self {
+        Self::new(ErrorKind::FailedPrecondition, op, cause)
+    }
+
+    pub fn invalid(op: &'static str, cause: impl Display) -> Self {
+        Self::new(ErrorKind::InvalidArgument, op, cause)
+    }
+
+    pub fn internal(op: &'static str, cause: impl Display) -> Self {
+        Self::new(ErrorKind::Internal, op, cause)
+    }
+
+    // a vanished file is not found, the others are io failures
+    pub fn io(op: &'static str, path: impl AsRef<Path>, err: std::io::Error) -> Self {
+        let kind = match err.kind() {
+            std::io::ErrorKind::NotFound => ErrorKind::NotFound,
+            _ => ErrorKind::Io,
+        };
+        Self::new(kind, op, err).at(path)
+    }
+
+    // the error sent back by a peer keeps its kind
+    pub fn from_status(op: &'static str, status: &Status) -> Self {
+        let kind = match status.code() {
+            Code::NotFound => ErrorKind::NotFound,
+            Code::Unavailable | Code::DeadlineExceeded => ErrorKind::Unavailable,
+            Code::Aborted => ErrorKind::Aborted,
+            Code::FailedPrecondition => ErrorKind::FailedPrecondition,
+            Code::InvalidArgument => ErrorKind::InvalidArgument,
+            // a connection dropped by a stopped peer has no code of its own
+            Code::Unknown if transport_failed(status) => ErrorKind::Unavailable,
+            _ => ErrorKind::Internal,
+        };
+        Self::new(kind, op, status.message())
+    }
+
+    // worth trying again later, nothing is wrong with the request itself
+    pub fn retriable(&self) -> bool {
+        matches!(self.kind, ErrorKind::Unavailable | ErrorKind::Aborted)
+    }
+
+    pub fn to_status(&self) -> Status {
+        let code = match self.kind {
+            ErrorKind::NotFound => Code::NotFound,
+            ErrorKind::Unavailable => Code::Unavailable,
+            ErrorKind::Aborted => Code::Aborted,
+            ErrorKind::FailedPrecondition => Code::FailedPrecondition,
+            ErrorKind::InvalidArgument => Code::InvalidArgument,
+            ErrorKind::Io | ErrorKind::Internal => Code::Internal,
+        };
+        Status::new(code, self.to_string())
+    }
+}
+
+impl From<TraError> for Status {
+    fn from(err: TraError) -> Self {
+        err.to_status()
+    }
+}
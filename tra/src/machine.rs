@@ -1,17 +1,54 @@
 use rand::Rng;
-use std::net::TcpListener as StdListener;
-use tonic::transport::server::TcpIncoming;
+use std::{
+    fmt::Display,
+    future::Future,
+    net::{IpAddr, SocketAddr, TcpListener as StdListener, ToSocketAddrs},
+    time::Duration,
+};
+use tonic::{transport::server::TcpIncoming, Status};
 
-use crate::{config::RpcChannel, MyResult};
+use crate::{
+    config::{config, RpcChannel},
+    error::{ErrorKind, TraError, TraResult},
+};
 
-#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
+// the address other machines use to reach a server, "<host>:<port>"
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
 pub struct ServeAddr {
+    host: String,
     port: u16,
 }
 
+impl Display for ServeAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.addr())
+    }
+}
+
 impl ServeAddr {
-    pub fn new(port: u16) -> Self {
-        Self { port }
+    pub fn new(host: impl AsRef<str>, port: u16) -> Self {
+        // the brackets of IPv6 are added back when formatting
+        let host = host.as_ref().trim_start_matches('[').trim_end_matches(']');
+        Self {
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    // "<host>:<port>", or "<port>" on the default host
+    pub fn parse(s: &str, default_host: &str) -> TraResult<Self> {
+        let invalid = || TraError::invalid("Parse Address", format!("invalid address \"{}\"", s));
+        match s.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() => {
+                Ok(Self::new(host, port.parse().map_err(|_| invalid())?))
+            }
+            Some(_) => Err(invalid()),
+            None => Ok(Self::new(default_host, s.parse().map_err(|_| invalid())?)),
+        }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
     }
 
     pub fn port(&self) -> u16 {
@@ -19,36 +56,354 @@ impl ServeAddr {
     }
 
     pub fn addr(&self) -> String {
-        format!("[::]:{}", self.port)
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
     }
 
     pub fn http_addr(&self) -> String {
         format!("http://{}", self.addr())
     }
+
+    // a server bound on all interfaces is reached by where its requests come from
+    pub fn from_sender(host: &str, port: u16, remote: Option<SocketAddr>) -> TraResult<Self> {
+        let unspecified = host
+            .parse::<IpAddr>()
+            .map_or(host.is_empty(), |ip| ip.is_unspecified());
+        match (unspecified, remote) {
+            (true, Some(remote)) => Ok(Self::new(remote.ip().to_string(), port)),
+            (true, None) => Err(TraError::invalid("Sender Address", "missing host")),
+            (false, _) => Ok(Self::new(host, port)),
+        }
+    }
+
+    pub fn socket_addr(&self) -> TraResult<SocketAddr> {
+        let unresolved = |cause: String| {
+            TraError::invalid(
+                "Resolve Address",
+                format!("failed to resolve \"{}\" : {}", self.addr(), cause),
+            )
+        };
+        self.addr()
+            .to_socket_addrs()
+            .map_err(|e| unresolved(e.to_string()))?
+            .next()
+            .ok_or_else(|| unresolved("no address".to_string()))
+    }
 }
 
-pub async fn get_listener() -> MyResult<(ServeAddr, TcpIncoming)> {
+// listen on the given host and port, or a random port on the host
+pub async fn get_listener(host: &str, port: Option<u16>) -> TraResult<(ServeAddr, TcpIncoming)> {
     let mut rng = rand::thread_rng();
     loop {
-        let port = rng.gen_range(49152..=65535);
-        let listener = StdListener::bind(format!("[::]:{}", port));
-        if let Ok(listener) = listener {
-            listener
-                .set_nonblocking(true)
-                .or(Err("failed to set nonblocking"))?;
-            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
-            let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
-            break Ok((ServeAddr::new(port), incoming));
+        let bind_port = port.unwrap_or_else(|| rng.gen_range(49152..=65535));
+        let bind_addr = ServeAddr::new(host, bind_port);
+        let listener = StdListener::bind(bind_addr.socket_addr()?);
+        match listener {
+            Ok(listener) => {
+                listener
+                    .set_nonblocking(true)
+                    .map_err(|e| TraError::new(ErrorKind::Io, "Listen", e))?;
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+                break Ok((bind_addr, incoming));
+            }
+            Err(e) if port.is_some() => {
+                let cause = format!("failed to listen on {} : {}", bind_addr, e);
+                return Err(TraError::new(ErrorKind::Io, "Listen", cause));
+            }
+            Err(_) => {}
         }
     }
 }
 
-pub async fn channel_connect(serve_addr: &ServeAddr) -> MyResult<RpcChannel> {
+pub async fn channel_connect(serve_addr: &ServeAddr) -> TraResult<RpcChannel> {
+    let retry = &config().retry;
     let addr = serve_addr.http_addr();
-    let channel = RpcChannel::from_shared(addr).or(Err("failed to build channel"))?;
-    let channel = channel
-        .connect()
+    let endpoint = RpcChannel::from_shared(addr)
+        .map_err(|e| TraError::invalid("Connect", e))?
+        .connect_timeout(retry.connect_timeout);
+    let mut backoff = Backoff::new();
+    loop {
+        match endpoint.connect().await {
+            Ok(channel) => break Ok(channel),
+            Err(e) => {
+                if !backoff.wait().await {
+                    break Err(TraError::unavailable(
+                        "Connect",
+                        format!(
+                            "failed to connect {} after {} attempts : {}",
+                            serve_addr, retry.attempts, e
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+// each attempt has a deadline, and the retriable failures are tried again
+// the channel reconnects by itself, so a restarted peer is reached again
+pub async fn retry_rpc<T, F, Fut>(op: &'static str, mut rpc: F) -> TraResult<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Status>>,
+{
+    let mut backoff = Backoff::new();
+    loop {
+        let res = tokio::time::timeout(config().retry.rpc_timeout, rpc())
+            .await
+            .unwrap_or_else(|_| Err(Status::deadline_exceeded("rpc timeout")))
+            .map_err(|status| TraError::from_status(op, &status));
+        match res {
+            Err(e) if e.retriable() && backoff.wait().await => {}
+            res => break res,
+        }
+    }
+}
+
+struct Backoff {
+    attempt: u32,
+    delay: Duration,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Self {
+            attempt: 1,
+            delay: config().retry.initial_backoff,
+        }
+    }
+
+    // false if no attempt is left
+    async fn wait(&mut self) -> bool {
+        if self.attempt >= config().retry.attempts {
+            return false;
+        }
+        tokio::time::sleep(self.delay).await;
+        self.attempt += 1;
+        self.delay = (self.delay * 2).min(config().retry.max_backoff);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Once,
+        },
+        thread::JoinHandle,
+        time::Instant,
+    };
+
+    use tokio::sync::oneshot;
+    use tonic::Request;
+
+    use super::*;
+    use crate::{
+        config::{init_config, Config, ReplicaConfig, RetryConfig},
+        reptra::{peer_server::PeerServer, Reptra, RsyncClient, SyncParams, Void},
+    };
+
+    const ATTEMPTS: u32 = 4;
+    // 100 + 200 + 400 ms between the attempts
+    const GIVE_UP_AFTER: Duration = Duration::from_millis(700);
+    const RESTART_AFTER: Duration = Duration::from_millis(250);
+
+    fn setup() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            let mut config = Config::defaults();
+            config.retry = RetryConfig {
+                attempts: ATTEMPTS,
+                initial_backoff: Duration::from_millis(100),
+                max_backoff: Duration::from_millis(400),
+                connect_timeout: Duration::from_secs(1),
+                rpc_timeout: Duration::from_secs(2),
+            };
+            init_config(config);
+        });
+    }
+
+    // a port nobody listens on, kept by the test to restart its servers
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    // the replica roots of one test, removed when it ends
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new() -> Self {
+            static NEXT: AtomicU32 = AtomicU32::new(0);
+            let name = format!(
+                "tra-test-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            );
+            Self(std::env::temp_dir().join(name))
+        }
+
+        fn conf(&self, id: i32, port: u16) -> ReplicaConfig {
+            let mut conf = config().replica(id);
+            conf.root = self.0.join(format!("replica-{}", id));
+            conf.host = "127.0.0.1".to_string();
+            conf.port = Some(port);
+            std::fs::create_dir_all(&conf.root).unwrap();
+            conf
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // a replica on its own runtime, so that stopping it closes every connection
+    struct TestServer {
+        stop: Option<oneshot::Sender<()>>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl TestServer {
+        fn start(conf: ReplicaConfig) -> Self {
+            let (stop, stopped) = oneshot::channel();
+            let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+            let thread = std::thread::spawn(move || {
+                let rt = tokio::runtime::Runtime::new().unwrap();
+                rt.block_on(async {
+                    let _reptra = Reptra::new_start_service(&conf).await.unwrap();
+                    ready_tx.send(()).unwrap();
+                    let _ = stopped.await;
+                });
+            });
+            ready_rx.recv().unwrap();
+            Self {
+                stop: Some(stop),
+                thread: Some(thread),
+            }
+        }
+
+        fn start_later(conf: ReplicaConfig) -> std::thread::JoinHandle<Self> {
+            std::thread::spawn(move || {
+                std::thread::sleep(RESTART_AFTER);
+                Self::start(conf)
+            })
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = self.stop.take().unwrap().send(());
+            self.thread.take().unwrap().join().unwrap();
+        }
+    }
+
+    fn local(port: u16) -> ServeAddr {
+        ServeAddr::new("127.0.0.1", port)
+    }
+
+    async fn status(channel: &RpcChannel) -> TraResult<i32> {
+        retry_rpc("Status", || {
+            let mut client = RsyncClient::new(channel.clone());
+            async move { client.status(Request::new(Void {})).await }
+        })
         .await
-        .or(Err("failed to connect channel"))?;
-    Ok(channel)
+        .map(|res| res.into_inner().id)
+    }
+
+    #[tokio::test]
+    async fn retry_rpc_reaches_restarted_server() {
+        setup();
+        let dir = TestDir::new();
+        let addr = local(free_port());
+        let server = TestServer::start(dir.conf(1, addr.port()));
+        let channel = channel_connect(&addr).await.unwrap();
+        assert_eq!(status(&channel).await.unwrap(), 1);
+
+        drop(server);
+        let restart = TestServer::start_later(dir.conf(1, addr.port()));
+        let start = Instant::now();
+        assert_eq!(status(&channel).await.unwrap(), 1);
+        assert!(start.elapsed() < GIVE_UP_AFTER);
+        drop(restart.join().unwrap());
+    }
+
+    #[tokio::test]
+    async fn channel_connect_waits_for_server() {
+        setup();
+        let dir = TestDir::new();
+        let addr = local(free_port());
+        let restart = TestServer::start_later(dir.conf(1, addr.port()));
+        let start = Instant::now();
+        let channel = channel_connect(&addr).await.unwrap();
+        assert!(start.elapsed() >= RESTART_AFTER);
+        assert!(start.elapsed() < GIVE_UP_AFTER);
+        let _server = restart.join().unwrap();
+        assert_eq!(status(&channel).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn unavailable_peer_evicts_cached_channel() {
+        setup();
+        let dir = TestDir::new();
+        let from = local(free_port());
+        let puller = Reptra::new_start_service(&dir.conf(2, free_port()))
+            .await
+            .unwrap();
+        let peer_server = PeerServer {
+            replica: puller.replica.clone(),
+            channels: Default::default(),
+            notifier: Default::default(),
+        };
+        let pull = || peer_server.pull("", "", &from, SyncParams::default());
+
+        let server = TestServer::start(dir.conf(1, from.port()));
+        pull().await.unwrap();
+        assert!(peer_server.channels.read().await.contains_key(&from));
+
+        drop(server);
+        let err = pull().await.unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Unavailable);
+        assert!(!peer_server.channels.read().await.contains_key(&from));
+
+        let _server = TestServer::start(dir.conf(1, from.port()));
+        pull().await.unwrap();
+        assert!(peer_server.channels.read().await.contains_key(&from));
+    }
+
+    #[tokio::test]
+    async fn channel_connect_gives_up() {
+        setup();
+        let start = Instant::now();
+        let err = channel_connect(&local(free_port())).await.unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Unavailable);
+        assert!(err
+            .to_string()
+            .contains(&format!("after {} attempts", ATTEMPTS)));
+        assert!(start.elapsed() >= GIVE_UP_AFTER);
+    }
+
+    #[tokio::test]
+    async fn retry_rpc_gives_up() {
+        setup();
+        let calls = AtomicU32::new(0);
+        let res: TraResult<()> = retry_rpc("Down", || {
+            calls.fetch_add(1, Ordering::Relaxed);
+            async { Err(Status::unavailable("down")) }
+        })
+        .await;
+        assert_eq!(res.unwrap_err().kind, ErrorKind::Unavailable);
+        assert_eq!(calls.load(Ordering::Relaxed), ATTEMPTS);
+    }
 }
@@ -4,87 +4,561 @@ pub mod checker;
 pub mod config;
 pub mod conflicts;
 pub mod debugger;
+pub mod error;
 pub mod machine;
 pub mod replica;
 pub mod reptra;
 pub mod timestamp;
 
-use banner::{BannerOut, SyncBanner};
+use banner::{BannerOut, NotifyBanner, RegistryBanner, StatusBanner, SyncBanner};
 use centra::Centra;
-use checker::check_legal;
-use config::{BASE_REP_NUM, TRA_PORT};
+use checker::parse_sync_path;
+use config::{config, init_config, resolve_path, Config, Overrides, RpcChannel};
 use machine::{channel_connect, ServeAddr};
-use reptra::{Reptra, RsyncClient};
+use replica::session::Session;
+use reptra::{
+    request_cancel, request_clone, request_subscribe, request_sync, Reptra, RsyncClient,
+    SyncAction, SyncParams, SyncReport,
+};
 
-pub use config::MyResult;
+use error::{TraError, TraResult};
 use rustyline::error::ReadlineError;
+use std::{future::Future, io::IsTerminal, time::Duration};
 use tonic::Request;
 
-use crate::reptra::{SyncReq, Void};
-
-async fn sync_command(args: &Vec<&str>, centra: &Centra) -> MyResult<()> {
-    let id1: i32 = args.get(1).ok_or("")?.parse().or(Err(""))?;
-    let id2: i32 = args.get(2).ok_or("")?.parse().or(Err(""))?;
-    let path_rel = args.get(3).ok_or("")?.to_string();
-    if id1 as usize <= BASE_REP_NUM
-        && id2 as usize <= BASE_REP_NUM
-        && id1 != id2
-        && check_legal(&path_rel)
-    {
-        let addr2 = centra.get_addr(id2);
-        let channel = channel_connect(&addr2).await.unwrap();
-        let mut client = RsyncClient::new(channel);
-        let request = Request::new(SyncReq {
-            port: centra.get_addr(id1).port() as i32,
-            path_rel: path_rel.clone(),
-        });
-        SyncBanner::sync_request(
-            id1,
-            centra.get_addr(id1).port(),
-            id2,
-            centra.get_addr(id2).port(),
-            path_rel,
-        );
-        client.request_sync(request).await.unwrap();
-    } else {
-        return Err("".into());
+use crate::reptra::{ProgressReq, StatusRes, Void};
+
+// also `join`, the second replica is seeded and joins the group of the first one
+async fn sync_command(args: &[&str], centra: &Centra) -> TraResult<()> {
+    const USAGE: &str = "usage : sync|join [--dry-run] [--timeout <secs>] <id> <id> [root:]<path>";
+    let (mut params, args) = split_sync_flags("Sync Command", args)?;
+    if args.len() != 4 {
+        return Err(TraError::invalid("Sync Command", USAGE));
+    }
+    params.join = args[0] == "join";
+    let id1 = replica_id(args[1])?;
+    let id2 = replica_id(args[2])?;
+    if id1 == id2 {
+        return Err(TraError::invalid(
+            "Sync Command",
+            "a replica can not sync with itself",
+        ));
     }
+    let (root, path_rel) = sync_target("Sync Command", args[3])?;
+    // refuse early if either side is offline
+    let addr1 = centra.get_addr(id1).await?;
+    let (addr2, mut client) = connect_replica(centra, id2).await?;
+    SyncBanner::sync_request(id1, &addr1, id2, &addr2, args[3]);
+    let report = until_synced(&mut client, &addr1, root, path_rel, &params).await?;
+    SyncBanner::report(&report);
     Ok(())
 }
 
-async fn tree_command(args: &Vec<&str>, centra: &Centra) -> MyResult<()> {
-    let id: i32 = args.get(1).ok_or("")?.parse().or(Err(""))?;
-    if id as usize <= BASE_REP_NUM {
-        let addr = centra.get_addr(id);
-        let channel = channel_connect(&addr).await.unwrap();
-        let mut client = RsyncClient::new(channel);
-        client.tree(Request::new(Void {})).await.unwrap();
-    } else {
-        return Err("".into());
+// stop the session on whichever replica runs it
+async fn cancel_command(args: &[&str], centra: &Centra) -> TraResult<()> {
+    if args.len() != 2 {
+        return Err(TraError::invalid(
+            "Cancel Command",
+            "usage : cancel <session>",
+        ));
     }
+    let id = cancel_session(centra, args[1]).await?;
+    SyncBanner::cancelled(id, args[1]);
     Ok(())
 }
 
-#[tokio::main]
-async fn main() {
-    let mut centra = Centra::new(&ServeAddr::new(TRA_PORT));
+// the id of the replica which was running the session
+async fn cancel_session(centra: &Centra, session: &str) -> TraResult<i32> {
+    for health in centra.registry.health().await {
+        let Some(addr) = health.addr.filter(|_| health.online) else {
+            continue;
+        };
+        let mut client = RsyncClient::new(channel_connect(&addr).await?);
+        if request_cancel(&mut client, session).await.is_ok() {
+            return Ok(health.id);
+        }
+    }
+    Err(TraError::not_found(
+        "Cancel Session",
+        format!("no replica is running session {}", session),
+    ))
+}
+
+async fn bisync_command(args: &[&str], centra: &Centra) -> TraResult<()> {
+    let (params, args) = split_sync_flags("Bisync Command", args)?;
+    if args.len() != 4 {
+        return Err(TraError::invalid(
+            "Bisync Command",
+            "usage : bisync [--dry-run] [--timeout <secs>] <id> <id> [root:]<path>",
+        ));
+    }
+    let id1 = replica_id(args[1])?;
+    let id2 = replica_id(args[2])?;
+    if id1 == id2 {
+        return Err(TraError::invalid(
+            "Bisync Command",
+            "a replica can not sync with itself",
+        ));
+    }
+    let (root, path_rel) = sync_target("Bisync Command", args[3])?;
+    let (addr1, client1) = connect_replica(centra, id1).await?;
+    let (addr2, client2) = connect_replica(centra, id2).await?;
+    let first = SyncEnd {
+        id: id1,
+        addr: addr1,
+        client: client1,
+    };
+    let second = SyncEnd {
+        id: id2,
+        addr: addr2,
+        client: client2,
+    };
+    bisync(first, second, args[3], root, path_rel, params).await
+}
+
+// the empty replica `to` takes the whole root of replica `from` at once
+async fn clone_command(args: &[&str], centra: &Centra) -> TraResult<()> {
+    if args.len() != 3 && args.len() != 4 {
+        return Err(TraError::invalid(
+            "Clone Command",
+            "usage : clone <from-id> <to-id> [root]",
+        ));
+    }
+    let from = replica_id(args[1])?;
+    let to = replica_id(args[2])?;
+    if from == to {
+        return Err(TraError::invalid(
+            "Clone Command",
+            "a replica can not clone itself",
+        ));
+    }
+    let root = args.get(3).map_or("", |root| root);
+    let from_addr = centra.get_addr(from).await?;
+    let (to_addr, mut client) = connect_replica(centra, to).await?;
+    SyncBanner::clone_request(from, &from_addr, to, &to_addr, root);
+    let session = Session::new_id();
+    let runner = client.clone();
+    let clone = request_clone(&mut client, &from_addr, root.to_string(), session.clone());
+    let report = until_done(runner, &session, clone).await?;
+    SyncBanner::report(&report);
+    Ok(())
+}
+
+// replica `to` pulls the changes of replica `from` under the path as soon as they happen
+async fn subscribe_command(args: &[&str], centra: &Centra) -> TraResult<()> {
+    if args.len() != 4 {
+        return Err(TraError::invalid(
+            "Subscribe Command",
+            "usage : subscribe <from-id> <to-id> [root:]<path>",
+        ));
+    }
+    let from = replica_id(args[1])?;
+    let to = replica_id(args[2])?;
+    if from == to {
+        return Err(TraError::invalid(
+            "Subscribe Command",
+            "a replica can not subscribe to itself",
+        ));
+    }
+    let (root, path_rel) = sync_target("Subscribe Command", args[3])?;
+    let to_addr = centra.get_addr(to).await?;
+    let (_, mut client) = connect_replica(centra, from).await?;
+    request_subscribe(&mut client, to, &to_addr, root, path_rel).await?;
+    NotifyBanner::subscribe_request(from, to, args[3]);
+    Ok(())
+}
+
+async fn sync_all_command(args: &[&str], centra: &Centra) -> TraResult<()> {
+    if args.len() != 2 {
+        return Err(TraError::invalid(
+            "Sync All Command",
+            "usage : sync-all [root:]<path>",
+        ));
+    }
+    let (root, path_rel) = sync_target("Sync All Command", args[1])?;
+    let session = Session::new_id();
+    SyncBanner::session(&session);
+    let sync_all = centra.sync_all(&root, &path_rel, args[1], &session);
+    let convergence = interruptible(sync_all, || cancel_session(centra, &session)).await?;
+    SyncBanner::sync_all(&convergence, args[1]);
+    Ok(())
+}
+
+// "--dry-run" and "--timeout <secs>" right after the command name,
+// with a fresh session so that the sync can be cancelled
+fn split_sync_flags<'a, T: AsRef<str>>(
+    op: &'static str,
+    args: &'a [T],
+) -> TraResult<(SyncParams, Vec<&'a str>)> {
+    let mut args: Vec<&str> = args.iter().map(|arg| arg.as_ref()).collect();
+    let mut params = SyncParams {
+        session: Session::new_id(),
+        ..Default::default()
+    };
+    loop {
+        match args.get(1) {
+            Some(&"--dry-run") => {
+                params.dry_run = true;
+                args.remove(1);
+            }
+            Some(&"--timeout") => {
+                let secs = args
+                    .get(2)
+                    .and_then(|secs| secs.parse().ok())
+                    .filter(|secs| *secs > 0)
+                    .ok_or_else(|| TraError::invalid(op, "--timeout needs positive seconds"))?;
+                params.timeout = Some(Duration::from_secs(secs));
+                args.drain(1..3);
+            }
+            _ => break,
+        }
+    }
+    Ok((params, args))
+}
+
+async fn until_synced(
+    client: &mut RsyncClient<RpcChannel>,
+    from: &ServeAddr,
+    root: String,
+    path_rel: String,
+    params: &SyncParams,
+) -> TraResult<SyncReport> {
+    let runner = client.clone();
+    let sync = request_sync(client, from, root, path_rel, params);
+    until_done(runner, &params.session, sync).await
+}
+
+// the session runs on the replica behind `client`, which streams its progress
+async fn until_done<T>(
+    client: RsyncClient<RpcChannel>,
+    session: &str,
+    work: impl Future<Output = TraResult<T>>,
+) -> TraResult<T> {
+    SyncBanner::session(session);
+    let progress = tokio::spawn(show_progress(client.clone(), session.to_string()));
+    let cancel = || {
+        let mut client = client.clone();
+        async move { request_cancel(&mut client, session).await }
+    };
+    let res = interruptible(work, cancel).await;
+    progress.abort();
+    if std::io::stdout().is_terminal() {
+        SyncBanner::progress_clear();
+    }
+    res
+}
+
+// Ctrl-C stops the session at its next file instead of killing the command line,
+// every command which may run long goes through here, since once tokio takes
+// SIGINT over it is never delivered to the process again
+async fn interruptible<T, R, F>(
+    work: impl Future<Output = TraResult<T>>,
+    cancel: impl Fn() -> F,
+) -> TraResult<T>
+where
+    F: Future<Output = TraResult<R>>,
+{
+    tokio::pin!(work);
+    loop {
+        tokio::select! {
+            res = &mut work => return res,
+            _ = tokio::signal::ctrl_c() => {
+                if let Err(e) = cancel().await {
+                    BannerOut::cross(e.to_string());
+                }
+            }
+        }
+    }
+}
+
+// only on a terminal, and paused while a conflict prompt may be shown
+async fn show_progress(mut client: RsyncClient<RpcChannel>, session: String) {
+    if !std::io::stdout().is_terminal() {
+        return;
+    }
+    let Ok(res) = client.sync_progress(Request::new(ProgressReq { session })).await else {
+        return;
+    };
+    let mut stream = res.into_inner();
+    let mut tick = 0;
+    let mut paused = false;
+    while let Ok(Some(progress)) = stream.message().await {
+        if progress.conflicts > 0 {
+            if !paused {
+                SyncBanner::conflicts_pending(&progress);
+                paused = true;
+            }
+            continue;
+        }
+        paused = false;
+        SyncBanner::progress(&progress, tick);
+        tick += 1;
+    }
+}
+
+async fn tree_command(args: &[&str], centra: &Centra) -> TraResult<()> {
+    if args.len() != 2 {
+        return Err(TraError::invalid("Tree Command", "usage : tree <id>"));
+    }
+    let (_, mut client) = connect_replica(centra, replica_id(args[1])?).await?;
+    client
+        .tree(Request::new(Void {}))
+        .await
+        .map_err(|status| TraError::from_status("Tree", &status))?;
+    Ok(())
+}
+
+async fn status_command(args: &[&str], centra: &Centra) -> TraResult<()> {
+    if args.len() != 2 {
+        return Err(TraError::invalid("Status Command", "usage : status <id>"));
+    }
+    let (_, mut client) = connect_replica(centra, replica_id(args[1])?).await?;
+    StatusBanner::watches(&replica_status(&mut client).await?);
+    Ok(())
+}
+
+async fn replicas_command(args: &[&str], centra: &Centra) -> TraResult<()> {
+    if args.len() != 1 {
+        return Err(TraError::invalid("Replicas Command", "usage : replicas"));
+    }
+    for health in centra.registry.health().await {
+        RegistryBanner::health(&health);
+    }
+    Ok(())
+}
+
+// one of the configured replicas
+fn replica_id(arg: &str) -> TraResult<i32> {
+    match arg.parse() {
+        Ok(id) if config().contains(id) => Ok(id),
+        _ => Err(TraError::invalid(
+            "Replica Id",
+            format!("\"{}\" is not in 1..={}", arg, config().replicas),
+        )),
+    }
+}
+
+async fn connect_replica(
+    centra: &Centra,
+    id: i32,
+) -> TraResult<(ServeAddr, RsyncClient<RpcChannel>)> {
+    let addr = centra.get_addr(id).await?;
+    let channel = channel_connect(&addr).await?;
+    Ok((addr, RsyncClient::new(channel)))
+}
+
+fn report(res: TraResult<()>) {
+    if let Err(e) = res {
+        BannerOut::cross(e.to_string());
+    }
+}
+
+fn sync_target(op: &'static str, arg: &str) -> TraResult<(String, String)> {
+    parse_sync_path(arg).ok_or_else(|| TraError::invalid(op, "illegal path").at(arg))
+}
+
+// one replica of a bidirectional sync
+struct SyncEnd {
+    id: i32,
+    addr: ServeAddr,
+    client: RsyncClient<RpcChannel>,
+}
+
+// the second replica pulls first and resolves the conflicts, then the first one
+// pulls back in the same session, taking those decisions instead of prompting again
+async fn bisync(
+    mut first: SyncEnd,
+    mut second: SyncEnd,
+    path: &str,
+    root: String,
+    path_rel: String,
+    params: SyncParams,
+) -> TraResult<()> {
+    SyncBanner::sync_request(first.id, &first.addr, second.id, &second.addr, path);
+    let to_second = until_synced(
+        &mut second.client,
+        &first.addr,
+        root.clone(),
+        path_rel.clone(),
+        &params,
+    )
+    .await?;
+    SyncBanner::report(&to_second);
+    if !to_second.stopped.is_empty() || to_second.count(SyncAction::Aborted) > 0 {
+        SyncBanner::bisync_halted(first.id, second.id, path);
+        return Ok(());
+    }
+    let params = SyncParams {
+        decided: to_second.decisions(),
+        ..params
+    };
+    SyncBanner::sync_request(second.id, &second.addr, first.id, &first.addr, path);
+    let to_first = until_synced(&mut first.client, &second.addr, root, path_rel, &params).await?;
+    SyncBanner::report(&to_first);
+    SyncBanner::bisync(first.id, &to_first, second.id, &to_second, path);
+    Ok(())
+}
+
+// talk to a replica daemon by its address, without the centra
+async fn control(args: &[String]) -> TraResult<()> {
+    let addr = args
+        .get(2)
+        .ok_or_else(|| TraError::invalid("Control", "missing replica address"))?;
+    let addr = ServeAddr::parse(addr, &config().host)?;
+    let connect =
+        |addr: ServeAddr| async move { channel_connect(&addr).await.map(RsyncClient::new) };
+    let mut client = connect(addr.clone()).await?;
+    match args.get(3).map(|s| s.as_str()) {
+        Some("sync") | Some("join") => {
+            let (mut params, args) = split_sync_flags("Control", &args[3..])?;
+            if args.len() != 3 {
+                return Err(TraError::invalid(
+                    "Control",
+                    "usage : sync|join [--dry-run] [--timeout <secs>] <peer-addr> [root:]<path>",
+                ));
+            }
+            params.join = args[0] == "join";
+            let peer = ServeAddr::parse(args[1], &config().host)?;
+            let (root, path_rel) = sync_target("Control", args[2])?;
+            // the ids are only for the banner, and prove both ends are alive
+            let mut peer_client = connect(peer.clone()).await?;
+            let peer_id = replica_status(&mut peer_client).await?.id;
+            let id = replica_status(&mut client).await?.id;
+            SyncBanner::sync_request(peer_id, &peer, id, &addr, args[2]);
+            let report = until_synced(&mut client, &peer, root, path_rel, &params).await?;
+            SyncBanner::report(&report);
+        }
+        Some("cancel") if args.len() == 5 => {
+            request_cancel(&mut client, &args[4]).await?;
+            let id = replica_status(&mut client).await?.id;
+            SyncBanner::cancelled(id, &args[4]);
+        }
+        Some("bisync") => {
+            let (params, args) = split_sync_flags("Control", &args[3..])?;
+            if args.len() != 3 {
+                return Err(TraError::invalid(
+                    "Control",
+                    "usage : bisync [--dry-run] [--timeout <secs>] <peer-addr> [root:]<path>",
+                ));
+            }
+            let peer = ServeAddr::parse(args[1], &config().host)?;
+            let (root, path_rel) = sync_target("Control", args[2])?;
+            let mut peer_client = connect(peer.clone()).await?;
+            let first = SyncEnd {
+                id: replica_status(&mut peer_client).await?.id,
+                addr: peer,
+                client: peer_client,
+            };
+            let second = SyncEnd {
+                id: replica_status(&mut client).await?.id,
+                addr,
+                client,
+            };
+            bisync(first, second, args[2], root, path_rel, params).await?;
+        }
+        Some("clone") if args.len() == 5 || args.len() == 6 => {
+            let peer = ServeAddr::parse(&args[4], &config().host)?;
+            let root = args.get(5).map_or("", |root| root.as_str());
+            let mut peer_client = connect(peer.clone()).await?;
+            let peer_id = replica_status(&mut peer_client).await?.id;
+            let id = replica_status(&mut client).await?.id;
+            SyncBanner::clone_request(peer_id, &peer, id, &addr, root);
+            let session = Session::new_id();
+            let runner = client.clone();
+            let clone = request_clone(&mut client, &peer, root.to_string(), session.clone());
+            let report = until_done(runner, &session, clone).await?;
+            SyncBanner::report(&report);
+        }
+        Some("subscribe") if args.len() == 6 => {
+            let peer = ServeAddr::parse(&args[4], &config().host)?;
+            let (root, path_rel) = sync_target("Control", &args[5])?;
+            let id = replica_status(&mut client).await?.id;
+            let mut peer_client = connect(peer).await?;
+            let peer_id = replica_status(&mut peer_client).await?.id;
+            request_subscribe(&mut peer_client, id, &addr, root, path_rel).await?;
+            NotifyBanner::subscribe_request(peer_id, id, &args[5]);
+        }
+        Some("tree") if args.len() == 4 => {
+            client
+                .tree(Request::new(Void {}))
+                .await
+                .map_err(|status| TraError::from_status("Tree", &status))?;
+        }
+        Some("status") if args.len() == 4 => {
+            StatusBanner::watches(&replica_status(&mut client).await?);
+        }
+        _ => return Err(TraError::invalid("Control", "unknown control command")),
+    }
+    Ok(())
+}
+
+async fn replica_status(client: &mut RsyncClient<RpcChannel>) -> TraResult<StatusRes> {
+    client
+        .status(Request::new(Void {}))
+        .await
+        .map(|res| res.into_inner())
+        .map_err(|status| TraError::from_status("Status", &status))
+}
+
+const USAGE: &str = "usage: tra [--config <file>] [--replicas <n>] [--host <host>] [--port <port>] [centra | replica <id> [root] [centra-addr] | ctl <replica-addr> <sync|join [--dry-run] [--timeout <secs>] <peer-addr> <path> | cancel <session> | bisync [--dry-run] [--timeout <secs>] <peer-addr> <path> | clone <peer-addr> [root] | subscribe <peer-addr> <path> | tree | status>]";
+
+// run a single replica as a daemon, registered to the centra
+async fn replica_daemon(args: &[String]) -> TraResult<()> {
+    let id: i32 = args
+        .get(2)
+        .ok_or_else(|| TraError::invalid("Replica", "missing replica id"))?
+        .parse()
+        .map_err(|_| TraError::invalid("Replica", "invalid replica id"))?;
+    let mut conf = config().replica(id);
+    if let Some(root) = args.get(3) {
+        let cwd = std::env::current_dir().map_err(|e| TraError::io("Replica", ".", e))?;
+        conf.root = resolve_path(&cwd, root);
+    }
+    // "<host>:<port>", or only the port of a centra on the configured host
+    let centra_addr = match args.get(4) {
+        Some(addr) => ServeAddr::parse(addr, &config().host)?,
+        None => ServeAddr::new(&config().host, config().port),
+    };
+    let reptra = Reptra::new_start_service(&conf).await?;
+    StatusBanner::serving(id, &reptra.serve_addr);
+    // the centra is optional, the replica is still controlled by `tra ctl`
+    if let Err(e) = reptra.send_port(&centra_addr).await {
+        StatusBanner::standalone(id, &centra_addr, e);
+    }
+    // also registers once the centra comes up
+    reptra.keep_alive(&centra_addr);
+    reptra.watching().await
+}
+
+// the central command line, spawn the replicas as threads if needed
+async fn centra_cli(spawn_replicas: bool) {
+    let centra_addr = ServeAddr::new(&config().host, config().port);
+    let mut centra = Centra::new(&centra_addr);
     centra.start_services().await;
 
-    for id in 1..=BASE_REP_NUM {
-        std::thread::spawn(move || {
-            let rt = tokio::runtime::Runtime::new().unwrap();
-            rt.block_on(async {
-                let reptra = Reptra::new_start_service(id as i32)
-                    .await
-                    .expect("failed to start");
-                reptra.send_port(&ServeAddr::new(TRA_PORT)).await.unwrap();
-                // reptra_greet_test(id as i32, &ServeAddr::new(TRA_PORT)).await.unwrap();
-                reptra.watching().await;
+    if spawn_replicas {
+        for id in 1..=config().replicas {
+            let conf = config().replica(id as i32);
+            let centra_addr = centra_addr.clone();
+            std::thread::spawn(move || {
+                let rt = tokio::runtime::Runtime::new().unwrap();
+                rt.block_on(async {
+                    let reptra = Reptra::new_start_service(&conf)
+                        .await
+                        .expect("failed to start");
+                    reptra.send_port(&centra_addr).await.unwrap();
+                    reptra.keep_alive(&centra_addr);
+                    // reptra_greet_test(id as i32, &centra_addr).await.unwrap();
+                    reptra.watching().await;
+                });
             });
-        });
+        }
     }
 
-    centra.collect_ports(BASE_REP_NUM).await;
+    if spawn_replicas {
+        centra.wait_replicas(config().replicas).await;
+    }
+    centra.start_scheduler();
 
     let mut rl = rustyline::DefaultEditor::new().unwrap();
     loop {
@@ -93,14 +567,24 @@ async fn main() {
             Ok(line) => {
                 let args = line.trim().split_whitespace().collect::<Vec<&str>>();
                 if !args.is_empty() {
-                    if args[0] == "sync" {
-                        if sync_command(&args, &centra).await.is_err() {
-                            BannerOut::cross("Invalid input");
-                        }
+                    if args[0] == "sync" || args[0] == "join" {
+                        report(sync_command(&args, &centra).await);
+                    } else if args[0] == "cancel" {
+                        report(cancel_command(&args, &centra).await);
+                    } else if args[0] == "clone" {
+                        report(clone_command(&args, &centra).await);
+                    } else if args[0] == "subscribe" {
+                        report(subscribe_command(&args, &centra).await);
+                    } else if args[0] == "sync-all" {
+                        report(sync_all_command(&args, &centra).await);
+                    } else if args[0] == "bisync" {
+                        report(bisync_command(&args, &centra).await);
                     } else if args[0] == "tree" {
-                        if tree_command(&args, &centra).await.is_err() {
-                            BannerOut::cross("Invalid input");
-                        }
+                        report(tree_command(&args, &centra).await);
+                    } else if args[0] == "status" {
+                        report(status_command(&args, &centra).await);
+                    } else if args[0] == "replicas" {
+                        report(replicas_command(&args, &centra).await);
                     } else if args[0] == "exit" {
                         if args.len() == 1 {
                             break;
@@ -121,3 +605,39 @@ async fn main() {
 
     println!("Shutting down the command line interface ...");
 }
+
+#[tokio::main]
+async fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+    let config = Overrides::parse(&mut args).and_then(|overrides| Config::load(&overrides));
+    match config {
+        Ok(config) => init_config(config),
+        Err(e) => {
+            BannerOut::cross(e.to_string());
+            println!("{}", USAGE);
+            std::process::exit(1);
+        }
+    }
+    match args.get(1).map(|s| s.as_str()) {
+        None => centra_cli(true).await,
+        Some("centra") if args.len() == 2 => centra_cli(false).await,
+        Some("ctl") => {
+            if let Err(e) = control(&args).await {
+                BannerOut::cross(e.to_string());
+                println!("{}", USAGE);
+                std::process::exit(1);
+            }
+        }
+        Some("replica") => {
+            if let Err(e) = replica_daemon(&args).await {
+                BannerOut::cross(e.to_string());
+                println!("{}", USAGE);
+                std::process::exit(1);
+            }
+        }
+        Some(_) => {
+            println!("{}", USAGE);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use async_recursion::async_recursion;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{mpsc, RwLock},
+};
+use tonic::{Request, Status};
+
+use crate::{
+    config::RpcChannel,
+    error::{TraError, TraResult},
+    reptra::{RsyncClient, SyncAction, SyncReport, TreeEntry, TreeReq},
+    timestamp::{SingletonTime, VectorTime},
+};
+
+use super::{
+    meta::{file_stamp, Holding, Meta},
+    node::{Node, NodeData, NodeStatus},
+    path_local::PathLocal,
+    report::{SyncRecorder, Transfer},
+    session::Session,
+    sync_root::{check_stamp, SyncRoot},
+};
+
+// below the default message limit of gRPC
+const CLONE_CHUNK: usize = 1 << 20;
+
+impl SyncRoot {
+    // the whole root in preorder, each file only if unchanged since recorded,
+    // a file changed halfway fails the clone, which leaves nothing on the other side
+    pub async fn send_tree(&self, tx: &mpsc::Sender<Result<TreeEntry, Status>>) -> TraResult<()> {
+        if self.meta.holding(&self.base_node.path) != Holding::Full {
+            return Err(TraError::precondition(
+                "Fetch Tree",
+                "a partial replica can not be cloned",
+            ));
+        }
+        let counter = self.read_counter().await;
+        let mut nodes = Vec::new();
+        preorder(&self.base_node, &mut nodes).await;
+        for node in nodes {
+            let is_dir = node.path.is_dir();
+            let mut entry = {
+                let data = node.data.read().await;
+                TreeEntry::from_data(&node.path, &data, is_dir)
+            };
+            // like a query, the peer knows this root up to now
+            entry.sync_time.insert(self.meta.id, counter);
+            if is_dir {
+                send(tx, entry).await?;
+            } else {
+                self.send_file(tx, &node.path, entry).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // read a chunk at a time, the first one goes with the entry itself
+    async fn send_file(
+        &self,
+        tx: &mpsc::Sender<Result<TreeEntry, Status>>,
+        path: &PathLocal,
+        entry: TreeEntry,
+    ) -> TraResult<()> {
+        let mod_time = entry.mod_time.clone().into();
+        let stamp = self.recorded_stamp("Fetch Tree", path, &mod_time).await?;
+        let mut file = tokio::fs::File::open(path)
+            .await
+            .map_err(|e| TraError::io("Fetch Tree", path, e))?;
+        let path_rel = entry.path_rel.clone();
+        let mut first = Some(entry);
+        loop {
+            let mut data = Vec::with_capacity(CLONE_CHUNK);
+            (&mut file)
+                .take(CLONE_CHUNK as u64)
+                .read_to_end(&mut data)
+                .await
+                .map_err(|e| TraError::io("Fetch Tree", path, e))?;
+            let entry = match first.take() {
+                Some(entry) => TreeEntry { data, ..entry },
+                None if data.is_empty() => break,
+                None => TreeEntry {
+                    path_rel: path_rel.clone(),
+                    data,
+                    chunk: true,
+                    ..Default::default()
+                },
+            };
+            send(tx, entry).await?;
+        }
+        check_stamp("Fetch Tree", path, &stamp).await
+    }
+
+    // an empty replica takes the root of the peer as if it had synced all of it,
+    // without the signatures of the sync, since there is nothing to diff against
+    pub async fn handle_clone(
+        &self,
+        client: RsyncClient<RpcChannel>,
+        session: Arc<Session>,
+    ) -> TraResult<SyncReport> {
+        let wd = {
+            let base = self.base_node.data.read().await;
+            for child in base.children.values() {
+                if child.data.read().await.status.exist() {
+                    return Err(TraError::precondition(
+                        "Clone",
+                        "the root is not empty, join the group instead",
+                    ));
+                }
+            }
+            base.wd.clone()
+        };
+        if self.meta.holding(&self.base_node.path) != Holding::Full {
+            return Err(TraError::precondition(
+                "Clone",
+                "a partial replica can not be cloned into",
+            ));
+        }
+        let time = self.add_counter().await;
+        let report = SyncRecorder::default();
+        let mut written = Vec::new();
+        self.meta.watch.freeze_watch(&wd).await;
+        let res = self
+            .receive_tree(client, time, &report, &session, &mut written)
+            .await;
+        if res.is_err() {
+            // nothing is recorded yet, so a failed clone leaves the root empty again
+            for path in written {
+                let _ = match path.is_dir() {
+                    true => tokio::fs::remove_dir_all(&path).await,
+                    false => tokio::fs::remove_file(&path).await,
+                };
+            }
+        }
+        self.meta.watch.unfreeze_watch(&wd).await;
+        res?;
+        Ok(SyncReport {
+            session: session.id.clone(),
+            ..report.finish()
+        })
+    }
+
+    async fn receive_tree(
+        &self,
+        mut client: RsyncClient<RpcChannel>,
+        time: i32,
+        report: &SyncRecorder,
+        session: &Session,
+        written: &mut Vec<PathLocal>,
+    ) -> TraResult<()> {
+        let request = Request::new(TreeReq {
+            root: self.meta.root.clone(),
+        });
+        let mut stream = client
+            .fetch_tree(request)
+            .await
+            .map_err(|status| TraError::from_status("Fetch Tree", &status))?
+            .into_inner();
+        let mut root = None;
+        // by the relative path of the parent
+        let mut entries: HashMap<String, Vec<(PathLocal, TreeEntry)>> = HashMap::new();
+        let mut bytes: HashMap<String, u64> = HashMap::new();
+        let mut skipped: Vec<String> = Vec::new();
+        while let Some(mut entry) = stream
+            .message()
+            .await
+            .map_err(|status| TraError::from_status("Fetch Tree", &status))?
+        {
+            // nothing is recorded before the whole tree is received, so a halted clone fails
+            if session.halt() {
+                let reason = session.halted().unwrap_or_default();
+                return Err(TraError::aborted(
+                    "Clone",
+                    format!("the session is {}", reason),
+                ));
+            }
+            if entry.path_rel.is_empty() {
+                root = Some(entry);
+                continue;
+            }
+            let path = self.request_path("Clone", &entry.path_rel)?;
+            session.visit(&path);
+            // the ignored ones are never synchronized, nor anything under them
+            let under_skipped = skipped
+                .iter()
+                .any(|dir| Path::new(&entry.path_rel).starts_with(dir));
+            if under_skipped || self.meta.is_ignored(&path) {
+                if !under_skipped {
+                    skipped.push(entry.path_rel);
+                }
+                continue;
+            }
+            let parent = Path::new(&entry.path_rel)
+                .parent()
+                .map_or(String::new(), |p| p.to_string_lossy().to_string());
+            if parent.is_empty() && !entry.chunk {
+                written.push(path.clone());
+            }
+            if entry.is_dir {
+                tokio::fs::create_dir_all(&path)
+                    .await
+                    .map_err(|e| TraError::io("Clone", &path, e))?;
+            } else {
+                let mut file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .append(entry.chunk)
+                    .truncate(!entry.chunk)
+                    .open(&path)
+                    .await
+                    .map_err(|e| TraError::io("Clone", &path, e))?;
+                file.write_all(&entry.data)
+                    .await
+                    .map_err(|e| TraError::io("Clone", &path, e))?;
+                *bytes.entry(entry.path_rel.clone()).or_default() += entry.data.len() as u64;
+                session.transferred(&path, entry.data.len() as u64);
+            }
+            if !entry.chunk {
+                entry.data = Vec::new();
+                entries.entry(parent).or_default().push((path, entry));
+            }
+        }
+        let root = root.ok_or_else(|| TraError::internal("Clone", "the root is not sent"))?;
+
+        let mut base = self.base_node.data.write().await;
+        base.mod_time.check_max(&root.mod_time.into());
+        base.sync_time.check_max(&root.sync_time.into());
+        base.sync_time.update_one(self.meta.id, time);
+        // the deleted ones left in the tree keep what they know
+        for (path, entry) in entries.remove("").unwrap_or_default() {
+            let child =
+                Node::new_cloned(&self.meta, path, entry, time, &mut entries, report, &bytes).await;
+            base.children.insert(child.file_name(), child);
+        }
+        Ok(())
+    }
+}
+
+impl Node {
+    // the node with the times of the peer, known up to the clone by this replica
+    #[async_recursion]
+    async fn new_cloned(
+        meta: &Arc<Meta>,
+        path: PathLocal,
+        entry: TreeEntry,
+        time: i32,
+        entries: &mut HashMap<String, Vec<(PathLocal, TreeEntry)>>,
+        report: &SyncRecorder,
+        bytes: &HashMap<String, u64>,
+    ) -> Arc<Node> {
+        let mut sync_time: VectorTime = entry.sync_time.into();
+        sync_time.update_one(meta.id, time);
+        let mut data = NodeData {
+            children: HashMap::new(),
+            mod_time: entry.mod_time.into(),
+            sync_time,
+            create_time: SingletonTime::new(entry.create_id, entry.create_time),
+            status: NodeStatus::Exist,
+            wd: meta.watch.add_watch(&path).await,
+            stamp: file_stamp(&path).await,
+        };
+        for (child_path, child_entry) in entries.remove(&entry.path_rel).unwrap_or_default() {
+            let child =
+                Node::new_cloned(meta, child_path, child_entry, time, entries, report, bytes).await;
+            data.children.insert(child.file_name(), child);
+        }
+        if entry.is_dir {
+            report.record(&path, SyncAction::Created, "dir");
+        } else {
+            let file_bytes = bytes.get(&entry.path_rel).copied().unwrap_or_default();
+            let transfer = Transfer {
+                delta_bytes: file_bytes,
+                file_bytes,
+            };
+            report.record_transfer(&path, SyncAction::Created, "file", transfer);
+        }
+        Arc::new(Node {
+            path,
+            meta: meta.clone(),
+            data: RwLock::new(data),
+        })
+    }
+}
+
+impl TreeEntry {
+    fn from_data(path: &PathLocal, data: &NodeData, is_dir: bool) -> Self {
+        Self {
+            path_rel: path.to_rel(),
+            is_dir,
+            create_id: data.create_time.create_id(),
+            create_time: data.create_time.time(),
+            mod_time: data.mod_time.clone().into(),
+            sync_time: data.sync_time.clone().into(),
+            data: Vec::new(),
+            chunk: false,
+        }
+    }
+}
+
+// the existing nodes, each dir before its children
+#[async_recursion]
+async fn preorder(node: &Arc<Node>, out: &mut Vec<Arc<Node>>) {
+    let data = node.data.read().await;
+    if data.status.deleted() {
+        return;
+    }
+    out.push(node.clone());
+    let children: Vec<Arc<Node>> = data.children.values().cloned().collect();
+    drop(data);
+    for child in children {
+        preorder(&child, out).await;
+    }
+}
+
+async fn send(tx: &mpsc::Sender<Result<TreeEntry, Status>>, entry: TreeEntry) -> TraResult<()> {
+    tx.send(Ok(entry))
+        .await
+        .map_err(|_| TraError::aborted("Fetch Tree", "the clone is dropped by the peer"))
+}
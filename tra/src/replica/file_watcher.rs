@@ -1,4 +1,6 @@
-use std::{collections::HashMap, ffi::OsStr, os::raw::c_int, path::Path, sync::Arc};
+use std::{
+    collections::HashMap, ffi::OsStr, os::raw::c_int, path::Path, sync::Arc, time::SystemTime,
+};
 
 use inotify::{Event, Inotify, WatchDescriptor, WatchMask, Watches};
 use lazy_static::lazy_static;
@@ -6,10 +8,11 @@ use tokio::sync::RwLock;
 
 use crate::{
     banner::{BannerOut, LocalBanner},
-    debug, MyResult,
+    debug,
+    error::{TraError, TraResult},
 };
 
-use super::path_local::PathLocal;
+use super::{node::ModType, path_local::PathLocal};
 
 lazy_static! {
     static ref WATCH_EVENTS: WatchMask = WatchMask::CREATE
@@ -19,10 +22,14 @@ lazy_static! {
         | WatchMask::MOVED_TO;
 }
 
+// the (is_dir, mtime, len) of each entry in a polled directory
+type DirSnapshot = HashMap<String, (bool, Option<SystemTime>, u64)>;
+
 pub struct FileWatcher {
     pub inotify: Inotify,
     pub wd_map: Arc<RwLock<HashMap<WatchDescriptor, PathLocal>>>,
     pub freeze_count_map: Arc<RwLock<HashMap<c_int, usize>>>,
+    pub polled: Arc<RwLock<HashMap<PathLocal, DirSnapshot>>>,
 }
 
 #[derive(Clone)]
@@ -30,6 +37,16 @@ pub struct WatchIfc {
     watches: Watches,
     wd_map: Arc<RwLock<HashMap<WatchDescriptor, PathLocal>>>,
     freeze_count_map: Arc<RwLock<HashMap<c_int, usize>>>,
+    // the directories which failed to be watched, fall back to polling
+    polled: Arc<RwLock<HashMap<PathLocal, DirSnapshot>>>,
+}
+
+// a local modification found by polling an unwatched directory
+pub struct PollEvent {
+    pub dir: PathLocal,
+    pub name: String,
+    pub ty: ModType,
+    pub is_dir: bool,
 }
 
 impl FileWatcher {
@@ -38,6 +55,7 @@ impl FileWatcher {
             inotify: Inotify::init().expect("Failed to initialize inotify"),
             wd_map: Arc::new(RwLock::new(HashMap::new())),
             freeze_count_map: Arc::new(RwLock::new(HashMap::new())),
+            polled: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
@@ -46,6 +64,7 @@ impl FileWatcher {
             watches: self.inotify.watches(),
             wd_map: self.wd_map.clone(),
             freeze_count_map: self.freeze_count_map.clone(),
+            polled: self.polled.clone(),
         }
     }
 
@@ -76,46 +95,150 @@ impl WatchIfc {
             BannerOut::warn(format!("Path does not exist : {}", path.display()));
         }
         if path.is_dir() {
-            LocalBanner::new_watch(path);
-            let wd = tmp_watches.add(path, *WATCH_EVENTS).unwrap();
-            self.wd_map.write().await.insert(wd.clone(), path.clone());
-            Some(wd)
+            match tmp_watches.add(path, *WATCH_EVENTS) {
+                Ok(wd) => {
+                    LocalBanner::new_watch(path);
+                    self.wd_map.write().await.insert(wd.clone(), path.clone());
+                    Some(wd)
+                }
+                Err(e) => {
+                    // mostly hitting fs.inotify.max_user_watches
+                    LocalBanner::watch_failed(path, e);
+                    let snapshot = snapshot_dir(path).await;
+                    self.polled.write().await.insert(path.clone(), snapshot);
+                    None
+                }
+            }
         } else {
             None
         }
     }
 
-    pub async fn remove_watch(&self, path: impl AsRef<Path>, wd: &WatchDescriptor) -> MyResult<()> {
-        LocalBanner::remove_watch(path);
+    // the directory is deleted, stop polling it
+    pub async fn remove_polling(&self, path: &PathLocal) {
+        self.polled.write().await.remove(path);
+    }
+
+    // the sync operation modifies the directory which contains the path,
+    // take a new snapshot so that polling will not report it as local modification
+    pub async fn refresh_polling(&self, path: &PathLocal) {
+        let mut parent = path.clone();
+        if parent.pop().is_none() {
+            return;
+        }
+        if let Some(snapshot) = self.polled.write().await.get_mut(&parent) {
+            *snapshot = snapshot_dir(&parent).await;
+        }
+    }
+
+    pub async fn poll(&self) -> Vec<PollEvent> {
+        let mut events = Vec::new();
+        // skip this round when some sync is in progress
+        if !self.freeze_count_map.read().await.is_empty() {
+            return events;
+        }
+        let mut polled = self.polled.write().await;
+        for (dir, old) in polled.iter_mut() {
+            let new = snapshot_dir(dir).await;
+            for (name, (is_dir, mtime, len)) in &new {
+                let tys = match old.get(name) {
+                    None => vec![(ModType::Create, *is_dir)],
+                    // replaced by another type, report as delete and create
+                    Some((old_is_dir, _, _)) if old_is_dir != is_dir => {
+                        vec![(ModType::Delete, *old_is_dir), (ModType::Create, *is_dir)]
+                    }
+                    Some((_, old_mtime, old_len))
+                        if !is_dir && (old_mtime, old_len) != (mtime, len) =>
+                    {
+                        vec![(ModType::Modify, false)]
+                    }
+                    _ => Vec::new(),
+                };
+                for (ty, is_dir) in tys {
+                    events.push(PollEvent {
+                        dir: dir.clone(),
+                        name: name.clone(),
+                        ty,
+                        is_dir,
+                    });
+                }
+            }
+            for (name, (is_dir, _, _)) in old.iter() {
+                if !new.contains_key(name) {
+                    events.push(PollEvent {
+                        dir: dir.clone(),
+                        name: name.clone(),
+                        ty: ModType::Delete,
+                        is_dir: *is_dir,
+                    });
+                }
+            }
+            *old = new;
+        }
+        events
+    }
+
+    pub async fn active_watches(&self) -> usize {
+        self.wd_map.read().await.len()
+    }
+
+    pub async fn unwatched_dirs(&self) -> usize {
+        self.polled.read().await.len()
+    }
+
+    pub async fn remove_watch(
+        &self,
+        path: impl AsRef<Path>,
+        wd: &WatchDescriptor,
+    ) -> TraResult<()> {
+        LocalBanner::remove_watch(&path);
         self.wd_map.write().await.remove(wd);
         let mut tmp_watches = self.watches.clone();
         tmp_watches
             .remove((*wd).clone())
-            .or(Err("Watch already removed"))?;
-        Ok(())
+            .map_err(|e| TraError::io("Remove Watch", path, e))
     }
 
     pub async fn query_path(&self, wd: &WatchDescriptor) -> Option<PathLocal> {
         self.wd_map.read().await.get(wd).cloned()
     }
 
-    pub async fn freeze_watch(&self, wd: &WatchDescriptor) {
+    // the directory may be unwatched, which still pauses the polling
+    pub async fn freeze_watch(&self, wd: &Option<WatchDescriptor>) {
         self.freeze_count_map
             .write()
             .await
-            .entry(wd.get_watch_descriptor_id())
+            .entry(freeze_id(wd))
             .and_modify(|v| *v += 1)
             .or_insert(1);
     }
 
-    pub async fn unfreeze_watch(&self, wd: &WatchDescriptor) {
+    pub async fn unfreeze_watch(&self, wd: &Option<WatchDescriptor>) {
         // manually sleep in case that the delay of inotify influences the sync-modification
         tokio::time::sleep(std::time::Duration::from_millis(50)).await;
         let mut mp = self.freeze_count_map.write().await;
-        mp.entry(wd.get_watch_descriptor_id())
-            .and_modify(|v| *v -= 1);
-        if mp.get(&wd.get_watch_descriptor_id()).unwrap() == &0 {
-            mp.remove(&wd.get_watch_descriptor_id());
+        mp.entry(freeze_id(wd)).and_modify(|v| *v -= 1);
+        if mp.get(&freeze_id(wd)).unwrap() == &0 {
+            mp.remove(&freeze_id(wd));
+        }
+    }
+}
+
+fn freeze_id(wd: &Option<WatchDescriptor>) -> c_int {
+    wd.as_ref().map_or(-1, |wd| wd.get_watch_descriptor_id())
+}
+
+async fn snapshot_dir(path: &PathLocal) -> DirSnapshot {
+    let mut snapshot = DirSnapshot::new();
+    if let Ok(mut entries) = tokio::fs::read_dir(path).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if let Ok(meta) = entry.metadata().await {
+                snapshot.insert(
+                    entry.file_name().to_string_lossy().to_string(),
+                    (meta.is_dir(), meta.modified().ok(), meta.len()),
+                );
+            }
         }
     }
+    snapshot
 }
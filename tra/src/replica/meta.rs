@@ -1,107 +1,212 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc, time::SystemTime};
 
 use fast_rsync::{apply, Signature};
 use tokio::{io::AsyncWriteExt, sync::Mutex};
 
 use crate::{
-    config::{RpcChannel, SIG_OPTION},
-    reptra::{FetchPatchReq, RsyncClient},
-    MyResult,
+    checker::{normal_path, IgnoreRules},
+    config::{config, MpscSender, ReplicaConfig},
+    conflicts::ConflictPolicy,
+    error::{TraError, TraResult},
+    machine::retry_rpc,
+    reptra::{notifier::Change, FetchPatchReq},
+    timestamp::VectorTime,
 };
 
-use super::{file_watcher::WatchIfc, path_local::PathLocal};
+use super::{file_watcher::WatchIfc, node::SyncOption, path_local::PathLocal, report::Transfer};
+
+// how much of a path is held by a partial replica
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Holding {
+    Full,
+    Partial,
+    Unheld,
+}
+
+// what the disk says about a file when its version is recorded,
+// a different stamp later means a change not recorded yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    len: u64,
+    modified: SystemTime,
+}
+
+// None for a dir or a missing file
+pub async fn file_stamp(path: &PathLocal) -> Option<FileStamp> {
+    let metadata = tokio::fs::metadata(path).await.ok()?;
+    if metadata.is_dir() {
+        return None;
+    }
+    Some(FileStamp {
+        len: metadata.len(),
+        modified: metadata.modified().ok()?,
+    })
+}
 
 pub struct Meta {
     pub(super) id: i32,
     pub(super) watch: WatchIfc,
     pub(super) c_lock: Arc<Mutex<()>>,
+    pub(super) ignore: IgnoreRules,
+    pub(super) conflict: ConflictPolicy,
+    // the name of the sync root
+    pub(super) root: String,
+    // the held subtrees if the root is partial
+    pub(super) partial: Option<Vec<PathBuf>>,
+    // the local modifications, announced to the subscribed peers
+    pub(super) changes: MpscSender<Change>,
 }
 
 impl Meta {
-    pub fn new(id: i32, watch: WatchIfc, c_lock: Arc<Mutex<()>>) -> Self {
-        Self { id, watch, c_lock }
+    pub fn new(
+        conf: &ReplicaConfig,
+        root: &str,
+        watch: WatchIfc,
+        c_lock: Arc<Mutex<()>>,
+        changes: MpscSender<Change>,
+    ) -> Self {
+        Self {
+            id: conf.id,
+            watch,
+            c_lock,
+            ignore: conf.ignore.clone(),
+            conflict: conf.conflict,
+            root: root.to_string(),
+            partial: conf.partial.get(root).cloned(),
+            changes,
+        }
+    }
+
+    // the notifier is gone only when the replica is shutting down
+    pub async fn changed(&self, path: &PathLocal) {
+        let change = Change {
+            root: self.root.clone(),
+            path_rel: path.to_rel(),
+        };
+        let _ = self.changes.send(change).await;
+    }
+
+    // never synchronized here, neither the ignored nor the unheld
+    pub fn is_ignored(&self, path: &PathLocal) -> bool {
+        self.ignore.is_ignored(&path.to_rel()) || self.holding(path) == Holding::Unheld
+    }
+
+    pub fn holding(&self, path: &PathLocal) -> Holding {
+        let Some(held) = &self.partial else {
+            return Holding::Full;
+        };
+        let path = normal_path(&path.to_rel());
+        if held.iter().any(|subtree| path.starts_with(subtree)) {
+            Holding::Full
+        } else if held.iter().any(|subtree| subtree.starts_with(&path)) {
+            // on the way to a held subtree
+            Holding::Partial
+        } else {
+            Holding::Unheld
+        }
     }
 }
 
-pub async fn read_bytes(path: &PathLocal) -> MyResult<Vec<u8>> {
+pub fn content_hash(data: &[u8]) -> Vec<u8> {
+    blake3::hash(data).as_bytes().to_vec()
+}
+
+pub async fn read_bytes(path: &PathLocal) -> TraResult<Vec<u8>> {
     if path.exists() {
-        match tokio::fs::read(path).await {
-            Ok(bytes) => return Ok(bytes),
-            Err(_) => Err("Read Bytes : read bytes failed".into()),
-        }
+        tokio::fs::read(path)
+            .await
+            .map_err(|e| TraError::io("Read Bytes", path, e))
     } else {
         Ok(Vec::new())
     }
 }
 
-pub async fn write_bytes(path: &PathLocal, data: impl AsRef<[u8]>) -> MyResult<()> {
+pub async fn write_bytes(path: &PathLocal, data: impl AsRef<[u8]>) -> TraResult<()> {
+    let io_err = |e| TraError::io("Write Bytes", path, e);
     let mut file = if path.exists() {
         tokio::fs::OpenOptions::new()
             .write(true)
             .truncate(true)
             .open(path)
             .await
-            .or(Err("Write Bytes : open file failed"))?
+            .map_err(io_err)?
     } else {
         let mut parent = path.clone();
-        parent.pop().ok_or("Write Bytes : get parent path failed")?;
+        parent
+            .pop()
+            .ok_or_else(|| TraError::invalid("Write Bytes", "no parent path").at(path))?;
         create_dir_all(&parent).await?;
-        tokio::fs::File::create(path)
-            .await
-            .or(Err("Write Bytes : create file failed"))?
+        tokio::fs::File::create(path).await.map_err(io_err)?
     };
-    file.write_all(data.as_ref())
-        .await
-        .or(Err("Write Bytes : write bytes to file failed"))?;
-    file.flush()
-        .await
-        .or(Err("Write Bytes : flush file failed"))?;
+    file.write_all(data.as_ref()).await.map_err(io_err)?;
+    file.flush().await.map_err(io_err)?;
     Ok(())
 }
 
+// the remote refuses the patch if its file is no longer the queried version
 pub async fn get_sync_bytes(
     path: &PathLocal,
-    mut client: RsyncClient<RpcChannel>,
-) -> MyResult<Vec<u8>> {
+    op: SyncOption,
+    mod_time: &VectorTime,
+) -> TraResult<(Vec<u8>, Transfer)> {
     let data = read_bytes(path).await?;
-    let sig = Signature::calculate(&data, SIG_OPTION);
+    let sig = Signature::calculate(&data, config().sig_option);
     let request = FetchPatchReq {
+        root: op.root,
         path_rel: path.to_rel(),
         sig: Vec::from(sig.serialized()),
+        mod_time: mod_time.clone().into(),
     };
-    let patch = client
-        .fetch_patch(request)
-        .await
-        .map_err(|e| "unable to fetch patch".to_string() + &e.to_string())?;
+    let patch = retry_rpc("Fetch Patch", || {
+        let mut client = op.client.clone();
+        let request = request.clone();
+        async move { client.fetch_patch(request).await }
+    })
+    .await
+    .map_err(|e| e.at(path))?;
     let delta = patch.into_inner().delta;
     let mut synced: Vec<u8> = Vec::new();
-    apply(&data, &delta, &mut synced).or(Err("Sync Bytes : apply failed"))?;
+    // a patch against a stale signature, the local file is changed meanwhile
+    apply(&data, &delta, &mut synced).map_err(|e| TraError::aborted("Sync Bytes", e).at(path))?;
     // info!("The size of data is {}, the size of patch is {}", data.len(), delta.len());
-    Ok(synced)
+    let transfer = Transfer {
+        delta_bytes: delta.len() as u64,
+        file_bytes: synced.len() as u64,
+    };
+    Ok((synced, transfer))
 }
 
-pub async fn sync_bytes(path: &PathLocal, client: RsyncClient<RpcChannel>) -> MyResult<()> {
-    let synced = get_sync_bytes(path, client).await?;
+// returns the stamp of the written file as well
+pub async fn sync_bytes(
+    path: &PathLocal,
+    op: SyncOption,
+    mod_time: &VectorTime,
+) -> TraResult<(Transfer, Option<FileStamp>)> {
+    let before = file_stamp(path).await;
+    let (synced, transfer) = get_sync_bytes(path, op, mod_time).await?;
+    // an edit arriving while the patch is fetched must not be overwritten
+    if file_stamp(path).await != before {
+        return Err(TraError::aborted("Sync Bytes", "changed locally during the sync").at(path));
+    }
     write_bytes(&path, synced).await?;
-    Ok(())
+    Ok((transfer, file_stamp(path).await))
 }
 
-pub async fn delete_file(path: &PathLocal) -> MyResult<()> {
+pub async fn delete_file(path: &PathLocal) -> TraResult<()> {
     tokio::fs::remove_file(path)
         .await
-        .or(Err("Delete File : remove file failed"))?;
-    Ok(())
+        .map_err(|e| TraError::io("Delete File", path, e))
 }
 
-pub async fn delete_empty_dir(path: &PathLocal) -> MyResult<()> {
+pub async fn delete_empty_dir(path: &PathLocal) -> TraResult<()> {
     // remove_dir will fail if the directory is not empty
     tokio::fs::remove_dir(path)
         .await
-        .or(Err("Delete Empty Dir : remove dir failed".into()))
+        .map_err(|e| TraError::io("Delete Empty Dir", path, e))
 }
 
-pub async fn create_dir_all(path: &PathLocal) -> MyResult<()> {
+pub async fn create_dir_all(path: &PathLocal) -> TraResult<()> {
     tokio::fs::create_dir_all(path)
         .await
-        .or(Err("Write Bytes : create dir failed".into()))
+        .map_err(|e| TraError::io("Create Dir", path, e))
 }
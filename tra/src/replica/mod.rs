@@ -1,120 +1,192 @@
+pub mod clone;
 pub mod file_watcher;
 pub mod meta;
 pub mod node;
 pub mod path_local;
 pub mod query;
+pub mod report;
+pub mod session;
+pub mod sync_root;
 
-use std::{ffi::OsStr, sync::Arc};
+use std::{collections::BTreeMap, ffi::OsStr, sync::Arc};
 
 use inotify::{Event, EventMask};
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{mpsc, Mutex};
+use tonic::Status;
 
 use crate::{
-    config::{sync_folder_prefix, RpcChannel},
-    reptra::{QueryRes, RsyncClient},
-    MyResult,
+    banner::BannerOut,
+    config::{MpscSender, ReplicaConfig, RpcChannel},
+    error::{TraError, TraResult},
+    reptra::{
+        notifier::Change, QueryRes, RsyncClient, StatusRes, SyncParams, SyncReport, TreeEntry,
+    },
 };
 
 use self::{
     file_watcher::WatchIfc,
-    meta::Meta,
-    node::{ModOption, ModType, Node, SyncOption},
+    node::ModType,
     path_local::PathLocal,
+    session::{Session, Sessions},
+    sync_root::SyncRoot,
 };
 
+// the roots are keyed by name, the default root has an empty name
 pub struct Replica {
-    pub meta: Arc<Meta>,
-    pub counter: RwLock<i32>,
-    pub base_node: Arc<Node>,
+    pub id: i32,
+    pub watch: WatchIfc,
+    pub roots: BTreeMap<String, Arc<SyncRoot>>,
+    pub sessions: Sessions,
 }
 
 impl Replica {
-    pub async fn read_counter(&self) -> i32 {
-        self.counter.read().await.clone()
-    }
-
-    pub async fn add_counter(&self) -> i32 {
-        let mut now = self.counter.write().await;
-        *now += 1;
-        *now
-    }
-
-    pub async fn new(id: i32, watch: WatchIfc, c_lock: Arc<Mutex<()>>) -> Self {
-        let meta = Arc::new(Meta::new(id, watch, c_lock));
-        let path = PathLocal::new_from_rel(sync_folder_prefix(id), "");
-        if !path.exists() {
-            tokio::fs::create_dir(&path).await.unwrap();
-        } else if !path.is_dir() {
-            panic!("The root path is not a directory!");
+    pub async fn new(
+        conf: &ReplicaConfig,
+        watch: WatchIfc,
+        c_lock: Arc<Mutex<()>>,
+        changes: MpscSender<Change>,
+    ) -> Self {
+        let mut roots = BTreeMap::new();
+        for (name, root) in conf.all_roots() {
+            let sync_root = SyncRoot::new(
+                conf,
+                &name,
+                root,
+                watch.clone(),
+                c_lock.clone(),
+                changes.clone(),
+            )
+            .await;
+            roots.insert(name, Arc::new(sync_root));
         }
-        let base_node = Node::new_base_node(&meta, path).await;
-        let base_node = Arc::new(base_node);
         Self {
-            meta,
-            counter: RwLock::new(0),
-            base_node,
+            id: conf.id,
+            watch,
+            roots,
+            sessions: Sessions::default(),
         }
     }
 
-    pub async fn init_all(&self) -> MyResult<()> {
-        // init the whole file tree, all inintial is in time 1
-        let init_counter = self.add_counter().await;
-        self.base_node.scan_all(init_counter).await?;
-        self.base_node
-            .data
-            .write()
-            .await
-            .mod_time
-            .update_one(self.meta.id, init_counter);
+    pub async fn init_all(&self) -> TraResult<()> {
+        for root in self.roots.values() {
+            root.init_all().await?;
+        }
         Ok(())
     }
 
     pub async fn tree(&self, show_detail: bool) {
-        self.base_node.sub_tree(show_detail, Vec::new()).await;
+        for root in self.roots.values() {
+            root.tree(show_detail).await;
+        }
+    }
+
+    pub fn root(&self, name: &str) -> TraResult<&Arc<SyncRoot>> {
+        self.roots.get(name).ok_or_else(|| {
+            TraError::not_found(
+                "Sync Root",
+                format!("unknown sync root \"{}\" in replica-{}", name, self.id),
+            )
+        })
+    }
+
+    // find the root which the local path belongs to
+    pub fn root_of(&self, path: &PathLocal) -> Option<&Arc<SyncRoot>> {
+        self.roots.values().find(|root| root.contains(path))
     }
 }
 
 impl Replica {
-    pub async fn handle_event(&self, event: &Event<&OsStr>) -> MyResult<()> {
-        let path = self
-            .meta
-            .watch
-            .query_path(&event.wd)
-            .await
-            .expect("should have this file watched")
-            .clone();
-        let walk = path.get_walk();
-        let op = ModOption {
-            ty: ModType::from_mask(&event.mask),
-            time: self.add_counter().await,
-            name: event.name.unwrap().to_str().unwrap().to_string(),
-            is_dir: event.mask.contains(EventMask::ISDIR),
+    pub async fn handle_event(&self, event: &Event<&OsStr>) -> TraResult<()> {
+        // the kernel dropped some events, which belong to no watch
+        if event.mask.contains(EventMask::Q_OVERFLOW) {
+            BannerOut::warn("Local Event: the event queue overflowed, rescanning");
+            for root in self.roots.values() {
+                root.rescan().await?;
+            }
+            return Ok(());
+        }
+        let Some(ty) = ModType::from_mask(&event.mask) else {
+            BannerOut::warn(format!(
+                "Local Event: unknown mask {:?}, event dropped",
+                event.mask
+            ));
+            return Ok(());
+        };
+        // the watch may be removed before its events are handled
+        let (Some(path), Some(name)) = (self.watch.query_path(&event.wd).await, event.name) else {
+            BannerOut::warn(format!(
+                "Local Event: unknown watch {} ({:?}), event dropped",
+                event.wd.get_watch_descriptor_id(),
+                event.mask
+            ));
+            return Ok(());
         };
-        self.base_node.handle_modify(walk, op).await
+        let root = self
+            .root_of(&path)
+            .ok_or_else(|| TraError::not_found("Event Handling", "root not found").at(&path))?;
+        root.handle_local(
+            &path,
+            name.to_string_lossy().to_string(),
+            ty,
+            event.mask.contains(EventMask::ISDIR),
+        )
+        .await
     }
 
-    pub async fn handle_query(&self, path: &String) -> MyResult<QueryRes> {
-        let path = PathLocal::new_from_rel(&self.base_node.path.prefix(), path);
-        let walk = path.get_walk();
-        let mut ret = self.base_node.handle_query(walk).await?;
-        ret.sync_time
-            .insert(self.meta.id, self.read_counter().await);
-        Ok(ret)
+    // the modifications in unwatched directories are found by polling
+    pub async fn handle_poll(&self) -> TraResult<()> {
+        for event in self.watch.poll().await {
+            let root = self
+                .root_of(&event.dir)
+                .ok_or_else(|| TraError::not_found("Polling", "root not found").at(&event.dir))?;
+            root.handle_local(&event.dir, event.name, event.ty, event.is_dir)
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn handle_status(&self) -> StatusRes {
+        StatusRes {
+            id: self.id,
+            active_watches: self.watch.active_watches().await as i32,
+            unwatched_dirs: self.watch.unwatched_dirs().await as i32,
+            roots: self.roots.keys().cloned().collect(),
+            sessions: self.sessions.list(),
+        }
+    }
+
+    pub async fn send_tree(
+        &self,
+        root: &str,
+        tx: &mpsc::Sender<Result<TreeEntry, Status>>,
+    ) -> TraResult<()> {
+        self.root(root)?.send_tree(tx).await
+    }
+
+    pub async fn handle_clone(
+        &self,
+        root: &str,
+        client: RsyncClient<RpcChannel>,
+        session: Arc<Session>,
+    ) -> TraResult<SyncReport> {
+        self.root(root)?.handle_clone(client, session).await
+    }
+
+    pub async fn handle_query(&self, root: &str, path: &str, hash: bool) -> TraResult<QueryRes> {
+        self.root(root)?.handle_query(path, hash).await
     }
 
     pub async fn handle_sync(
         &self,
-        path: &String,
+        root: &str,
+        path: &str,
         client: RsyncClient<RpcChannel>,
-    ) -> MyResult<()> {
-        let path = PathLocal::new_from_rel(self.base_node.path.prefix(), path);
-        let walk = path.get_walk();
-        let op = SyncOption {
-            time: self.add_counter().await,
-            client,
-        };
-        self.base_node.handle_sync(op, walk, &None).await?;
-        Ok(())
+        params: &SyncParams,
+        session: Arc<Session>,
+    ) -> TraResult<SyncReport> {
+        self.root(root)?
+            .handle_sync(path, client, params, session)
+            .await
     }
 
     pub fn clean(&mut self) {
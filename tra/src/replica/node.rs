@@ -9,17 +9,21 @@ use tonic::Request;
 use crate::{
     banner::{LocalBanner, SyncBanner},
     config::RpcChannel,
-    conflicts::manually_resolve,
-    replica::{
-        meta::{create_dir_all, delete_empty_dir, delete_file, sync_bytes},
-        Meta,
+    conflicts::{manually_resolve, ConflictPolicy},
+    error::{ErrorKind, TraError, TraResult},
+    machine::retry_rpc,
+    replica::meta::{
+        content_hash, create_dir_all, delete_empty_dir, delete_file, file_stamp, read_bytes,
+        sync_bytes, FileStamp, Holding, Meta,
     },
-    reptra::{QueryReq, QueryRes, RsyncClient},
+    reptra::{QueryReq, QueryRes, RsyncClient, SyncAction},
     timestamp::{SingletonTime, VectorTime},
-    MyResult,
 };
 
-use super::{path_local::PathLocal, query::RemoteData};
+use super::{
+    path_local::PathLocal, query::RemoteData, report::SyncRecorder, session::Session,
+    sync_root::INIT_TIME,
+};
 
 #[derive(Debug, Clone, PartialEq, Eq, Copy)]
 pub enum NodeStatus {
@@ -44,6 +48,8 @@ pub struct NodeData {
     pub create_time: SingletonTime,
     pub status: NodeStatus,
     pub wd: Option<WatchDescriptor>,
+    // the file on disk when its version was recorded
+    pub stamp: Option<FileStamp>,
 }
 
 pub struct Node {
@@ -55,7 +61,30 @@ pub struct Node {
 #[derive(Clone)]
 pub struct SyncOption {
     pub time: i32,
+    // the name of the sync root, the same on both replicas
+    pub root: String,
     pub client: RsyncClient<RpcChannel>,
+    pub report: SyncRecorder,
+    // walk the same decisions, but touch neither the disk nor the times
+    pub dry_run: bool,
+    // a seeded replica joining the group, the peer's version wins over
+    // the concurrent local one, and an identical one takes the peer's times
+    pub join: bool,
+    // carried from the other pass of a bisync, so a decided conflict is never prompted again
+    pub decided: Arc<HashMap<String, bool>>,
+    // nobody answers a prompt, the conflicts only a policy decides are left
+    pub unattended: bool,
+    pub session: Arc<Session>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TypeResolution {
+    Local,
+    Remote,
+    // keep both, the local one under another name
+    Aside,
+    // the remote one came after the local one, no conflict
+    Replace,
 }
 
 pub enum SyncType {
@@ -73,19 +102,20 @@ pub struct ModOption {
 }
 
 impl ModType {
-    pub fn from_mask(mask: &EventMask) -> Self {
+    // None for the masks which are not a modification, like an unmount
+    pub fn from_mask(mask: &EventMask) -> Option<Self> {
         if mask.contains(EventMask::CREATE) {
-            return ModType::Create;
+            Some(ModType::Create)
         } else if mask.contains(EventMask::DELETE) {
-            return ModType::Delete;
+            Some(ModType::Delete)
         } else if mask.contains(EventMask::MODIFY) {
-            return ModType::Modify;
+            Some(ModType::Modify)
         } else if mask.contains(EventMask::MOVED_TO) {
-            return ModType::MovedTo;
+            Some(ModType::MovedTo)
         } else if mask.contains(EventMask::MOVED_FROM) {
-            return ModType::MovedFrom;
+            Some(ModType::MovedFrom)
         } else {
-            panic!("Unknown event mask: {:?}", mask);
+            None
         }
     }
 }
@@ -118,15 +148,33 @@ impl NodeStatus {
 }
 
 impl SyncOption {
-    pub async fn query_data(&mut self, path: &PathLocal) -> MyResult<(RemoteData, bool)> {
-        let res = self
-            .client
-            .query(Request::new(QueryReq {
+    pub async fn query_data(&mut self, path: &PathLocal) -> TraResult<(RemoteData, bool)> {
+        self.query(path, false).await
+    }
+
+    // the content hash costs a read of the remote file, so it is asked for a conflict only
+    pub async fn query_hash(&mut self, path: &PathLocal) -> TraResult<RemoteData> {
+        Ok(self.query(path, true).await?.0)
+    }
+
+    // whether to take the remote version, if the conflict is decided already
+    pub fn decided(&self, path: &PathLocal) -> Option<bool> {
+        self.decided.get(&path.to_rel()).copied()
+    }
+
+    async fn query(&mut self, path: &PathLocal, hash: bool) -> TraResult<(RemoteData, bool)> {
+        let res = retry_rpc("Query", || {
+            let mut client = self.client.clone();
+            let request = Request::new(QueryReq {
+                root: self.root.clone(),
                 path_rel: path.to_rel(),
-            }))
-            .await
-            .map_err(|e| "query failed".to_string() + &e.to_string())?
-            .into_inner();
+                hash,
+            });
+            async move { client.query(request).await }
+        })
+        .await
+        .map_err(|e| e.at(path))?
+        .into_inner();
         Ok(res.to_data())
     }
 }
@@ -140,11 +188,20 @@ impl NodeData {
     }
 }
 
+// the default root is displayed as the replica itself
+pub fn root_display(id: i32, root: &str) -> String {
+    if root.is_empty() {
+        format!("replica-{}", id)
+    } else {
+        format!("replica-{}:{}", id, root)
+    }
+}
+
 impl Node {
     pub fn file_name(&self) -> String {
         self.path
             .file_name()
-            .map_or(format!("replica-{}", self.meta.id), |s| s)
+            .map_or(root_display(self.meta.id, &self.meta.root), |s| s)
     }
 
     #[async_recursion]
@@ -213,6 +270,7 @@ impl Node {
             create_time: SingletonTime::default(),
             status: NodeStatus::Exist,
             wd: meta.watch.add_watch(&path).await,
+            stamp: None,
         };
         Self {
             path,
@@ -230,6 +288,7 @@ impl Node {
             create_time,
             status: NodeStatus::Exist,
             wd: meta.watch.add_watch(path).await,
+            stamp: file_stamp(path).await,
         };
         Node {
             path: path.clone(),
@@ -248,6 +307,7 @@ impl Node {
             create_time: SingletonTime::new(0, 0),
             status: NodeStatus::Deleted,
             wd: None,
+            stamp: None,
         };
         Self {
             meta: meta.clone(),
@@ -272,74 +332,165 @@ impl Node {
 // recursive operation on node and node's data
 impl Node {
     // scan all the files (which are not detected before) in the directory
-    #[async_recursion]
-    pub async fn scan_all(&self, init_time: i32) -> MyResult<()> {
+    // the existing children are kept, so that rescanning a watched directory
+    // will not record the same file twice
+    pub async fn scan_all(&self, init_time: i32) -> TraResult<()> {
         let mut cur_data = self.data.write().await;
+        self.scan_data(&mut cur_data, init_time).await
+    }
+
+    #[async_recursion]
+    async fn scan_data(&self, cur_data: &mut NodeData, init_time: i32) -> TraResult<()> {
         let mut sub_files = tokio::fs::read_dir(self.path.as_ref())
             .await
-            .or(Err("Scan All Error : read dir error"))?;
+            .map_err(|e| TraError::io("Scan All", &self.path, e))?;
 
         // let mut join_set = tokio::task::JoinSet::new();
         let mut join_set = tokio::task::JoinSet::new();
         while let Some(sub_file) = sub_files.next_entry().await.unwrap() {
             let path = PathLocal::new_from_local(self.path.prefix(), sub_file.path());
-            let child = Arc::new(Node::new_from_create(&path, init_time, &self.meta).await);
-            cur_data.children.insert(child.file_name(), child.clone());
+            if self.meta.is_ignored(&path) {
+                continue;
+            }
+            let child = match cur_data.children.get(&path.file_name().unwrap()) {
+                Some(child) if child.data.read().await.status.exist() => child.clone(),
+                _ => {
+                    let child = Arc::new(Node::new_from_create(&path, init_time, &self.meta).await);
+                    cur_data.children.insert(child.file_name(), child.clone());
+                    child
+                }
+            };
             if child.path.is_dir() {
                 join_set.spawn(async move { child.scan_all(init_time).await });
             }
         }
 
         while let Some(res) = join_set.join_next().await {
-            res.or(Err("Scan All Error : join error"))??;
+            res.map_err(|e| TraError::internal("Scan All", e).at(&self.path))??;
+        }
+
+        // the children found by a rescan are newer than the directory itself
+        let children: Vec<Arc<Node>> = cur_data.children.values().cloned().collect();
+        for child in children {
+            cur_data
+                .mod_time
+                .check_max(&child.data.read().await.mod_time);
         }
 
         Ok(())
     }
 
+    // never changed since `init_all`, the mod time is still its own init stamp
+    fn seeded(&self, data: &NodeData) -> bool {
+        let init = SingletonTime::new(self.meta.id, INIT_TIME);
+        data.status.exist() && data.mod_time == VectorTime::from_singleton_time(&init)
+    }
+
+    // the whole tree is as `init_all` found it, the placeholders never existed here
+    #[async_recursion]
+    pub async fn all_seeded(&self) -> bool {
+        let data = self.data.read().await;
+        if data.status.deleted() && data.mod_time == VectorTime::default() {
+            return true;
+        }
+        if !self.seeded(&data) {
+            return false;
+        }
+        for child in data.children.values() {
+            if !child.all_seeded().await {
+                return false;
+            }
+        }
+        true
+    }
+
+    // the modifications and deletions the lost events would have reported,
+    // as (dir, name, type, is_dir), the creations are found by `scan_all`
+    #[async_recursion]
+    pub async fn stale_changes(&self, out: &mut Vec<(PathLocal, String, ModType, bool)>) {
+        let children: Vec<Arc<Node>> = self.data.read().await.children.values().cloned().collect();
+        for child in children {
+            let child_data = child.data.read().await;
+            if child_data.status.deleted() {
+                continue;
+            }
+            let name = child.file_name();
+            // only a file has a stamp
+            let is_dir = child_data.stamp.is_none();
+            if !child.path.exists() {
+                out.push((self.path.clone(), name, ModType::Delete, is_dir));
+            } else if !is_dir && file_stamp(&child.path).await != child_data.stamp {
+                out.push((self.path.clone(), name, ModType::Modify, false));
+            } else if is_dir {
+                drop(child_data);
+                child.stale_changes(out).await;
+            }
+        }
+    }
+
     #[async_recursion]
-    pub async fn handle_modify(&self, mut walk: Vec<String>, op: ModOption) -> MyResult<()> {
+    pub async fn handle_modify(&self, mut walk: Vec<String>, op: ModOption) -> TraResult<()> {
         if !walk.is_empty() {
             // not the target node yet
             let mut cur_data = self.data.write().await;
             let child_name = walk.pop().unwrap();
-            let child = cur_data
-                .children
-                .get(&child_name)
-                .ok_or("Event Handling Error : Node not found along the path")?;
+            let child = match cur_data.children.get(&child_name) {
+                Some(child) if child.data.read().await.status.exist() => child.clone(),
+                _ => {
+                    // the ancestor is missing in the tree, reconstruct it from disk,
+                    // the rescan records the target of this event as well
+                    let child_path = self.path.join_name(&child_name);
+                    if !child_path.is_dir() {
+                        LocalBanner::drop_event(&child_path);
+                        return Ok(());
+                    }
+                    LocalBanner::reconstruct(&child_path);
+                    let child =
+                        Arc::new(Node::new_from_create(&child_path, op.time, &self.meta).await);
+                    child.scan_all(op.time).await?;
+                    cur_data.children.insert(child_name, child);
+                    cur_data.mod_time.update_one(self.meta.id, op.time);
+                    return Ok(());
+                }
+            };
             child.handle_modify(walk, op.clone()).await?;
             cur_data.mod_time.update_one(self.meta.id, op.time);
         } else {
-            match op.ty {
-                ModType::Create | ModType::MovedTo => {
+            let target = self.data.read().await.children.get(&op.name).cloned();
+            let target = match target {
+                Some(child) if child.data.read().await.status.exist() => Some(child),
+                _ => None,
+            };
+            match (op.ty, target) {
+                (ModType::Create | ModType::MovedTo, _) => {
                     // create method : from parent node handling it
                     self.create_child(&op.name, op.time).await?;
                 }
-                ModType::Delete | ModType::MovedFrom => {
+                (ModType::Delete | ModType::MovedFrom, Some(deleted)) => {
                     let mut cur_data = self.data.write().await;
-                    let deleted = cur_data
-                        .children
-                        .get(&op.name)
-                        .ok_or("Delete Error : Node not found when handling Delete Event")?;
                     deleted.delete_node(op.time).await?;
                     cur_data.mod_time.update_one(self.meta.id, op.time);
                 }
-                ModType::Modify => {
+                (ModType::Modify, Some(modified)) => {
                     let mut cur_data = self.data.write().await;
-                    let modified = cur_data
-                        .children
-                        .get(&op.name)
-                        .ok_or("Modify Error : Node not found when handling Modify event")?;
                     modified.modify_node(op.time).await?;
                     cur_data.mod_time.update_one(self.meta.id, op.time);
                 }
+                (ModType::Modify, None) if self.path.join_name(&op.name).exists() => {
+                    // the creation is missed, record it as created now
+                    LocalBanner::reconstruct(&self.path.join_name(&op.name));
+                    self.create_child(&op.name, op.time).await?;
+                }
+                (ModType::Delete | ModType::MovedFrom | ModType::Modify, None) => {
+                    LocalBanner::drop_event(&self.path.join_name(&op.name));
+                }
             };
         };
         Ok(())
     }
 
     #[async_recursion]
-    pub async fn handle_query(&self, mut walk: Vec<String>) -> MyResult<QueryRes> {
+    pub async fn handle_query(&self, mut walk: Vec<String>) -> TraResult<QueryRes> {
         let cur_data = self.data.read().await;
 
         // deleted : return directly
@@ -355,6 +506,22 @@ impl Node {
         }
     }
 
+    // the recorded mod time, and the stamp of the file when it was recorded
+    #[async_recursion]
+    pub async fn handle_stamp(
+        &self,
+        mut walk: Vec<String>,
+    ) -> TraResult<(VectorTime, Option<FileStamp>)> {
+        let cur_data = self.data.read().await;
+        if !cur_data.status.deleted() {
+            if let Some(name) = walk.pop() {
+                let child = self.get_child(&cur_data, &name);
+                return child.handle_stamp(walk).await;
+            }
+        }
+        Ok((cur_data.mod_time.clone(), cur_data.stamp))
+    }
+
     // always sync remote -> local
     #[async_recursion]
     pub async fn handle_sync(
@@ -362,28 +529,35 @@ impl Node {
         op: SyncOption,
         mut walk: Vec<String>,
         p_wd: &Option<WatchDescriptor>,
-    ) -> MyResult<NodeStatus> {
+    ) -> TraResult<NodeStatus> {
         if !walk.is_empty() {
             // not the target node yet
             let mut cur_data = self.data.write().await;
             let np_wd = cur_data.wd.clone().or(p_wd.clone());
             let child = self.get_child(&cur_data, &walk.pop().unwrap());
-            let child_status = child.handle_sync(op, walk, &np_wd).await?;
+            let child_status = child.handle_sync(op.clone(), walk, &np_wd).await?;
 
-            if child_status.exist() {
+            if child_status.exist() && !op.dry_run {
                 cur_data.children.insert(child.file_name(), child);
             }
 
             if cur_data.status.deleted() && child_status.exist() {
                 // the node is tmp node, and the dir should already be created
                 SyncBanner::create_for_parent(&self.path);
+                op.report
+                    .record(&self.path, SyncAction::Created, "dir for the children");
+                if op.dry_run {
+                    return Ok(NodeStatus::Exist);
+                }
                 assert!(self.path.exists() && self.path.is_dir());
                 assert!(cur_data.wd.is_none());
 
                 cur_data.status.set_exist();
                 cur_data.wd = self.meta.watch.add_watch(&self.path).await;
             }
-            cur_data.pushup_mod().await;
+            if !op.dry_run {
+                cur_data.pushup_mod().await;
+            }
             return Ok(cur_data.status);
         } else {
             return self.sync_node(op, p_wd).await;
@@ -392,10 +566,41 @@ impl Node {
 }
 
 impl Node {
-    pub async fn create_child(&self, name: &String, time: i32) -> MyResult<()> {
+    pub async fn create_child(&self, name: &String, time: i32) -> TraResult<()> {
+        let existed = self.data.read().await.children.get(name).cloned();
+        if let Some(child) = existed {
+            let (exist, stamp) = {
+                let data = child.data.read().await;
+                (data.status.exist(), data.stamp)
+            };
+            if exist {
+                // already recorded by the rescan of a newly created parent,
+                // the event arrived after the watch was added
+                if child.path.is_dir() {
+                    child.scan_all(time).await?;
+                    let child_mod = child.data.read().await.mod_time.clone();
+                    self.data.write().await.mod_time.check_max(&child_mod);
+                    return Ok(());
+                }
+                if file_stamp(&child.path).await == stamp {
+                    return Ok(());
+                }
+                // saved over the file, like `mv tmp f` of an editor
+                child.modify_node(time).await?;
+                self.data
+                    .write()
+                    .await
+                    .mod_time
+                    .update_one(self.meta.id, time);
+                return Ok(());
+            }
+        }
+
         LocalBanner::create(&self.path, name);
 
         let child_path = self.path.join_name(name);
+        // the watch is added before the rescan, so the files created in the gap
+        // are either found by the rescan or reported by a later event
         let child = Arc::new(Node::new_from_create(&child_path, time, &self.meta).await);
         if child.path.is_dir() {
             child.scan_all(time).await?;
@@ -406,17 +611,18 @@ impl Node {
         Ok(())
     }
 
-    pub async fn modify_node(&self, time: i32) -> MyResult<()> {
+    pub async fn modify_node(&self, time: i32) -> TraResult<()> {
         LocalBanner::modify(&self.path);
 
         let mut data = self.data.write().await;
         data.mod_time.update_one(self.meta.id, time);
         data.sync_time.update_one(self.meta.id, time);
+        data.stamp = file_stamp(&self.path).await;
         Ok(())
     }
 
     #[async_recursion]
-    pub async fn delete_node(&self, time: i32) -> MyResult<()> {
+    pub async fn delete_node(&self, time: i32) -> TraResult<()> {
         LocalBanner::delete(&self.path);
 
         let mut cur_data = self.data.write().await;
@@ -428,14 +634,18 @@ impl Node {
         cur_data.mod_time.update_one(self.meta.id, time);
         cur_data.sync_time.update_one(self.meta.id, time);
         cur_data.status.set_deleted();
+        cur_data.stamp = None;
 
         // the file may not have a wd, just a file
         if let Some(wd) = cur_data.wd.take() {
             // the wd destoryed by OS or manually here
             let _ = self.meta.watch.remove_watch(self.path.as_ref(), &wd).await;
-            for (_, child) in cur_data.children.iter() {
-                child.delete_node(time).await?;
-            }
+        } else {
+            // or an unwatched directory
+            self.meta.watch.remove_polling(&self.path).await;
+        }
+        for (_, child) in cur_data.children.iter() {
+            child.delete_node(time).await?;
         }
 
         Ok(())
@@ -446,18 +656,38 @@ impl Node {
         &self,
         mut op: SyncOption,
         p_wd: &Option<WatchDescriptor>,
-    ) -> MyResult<NodeStatus> {
+    ) -> TraResult<NodeStatus> {
         let mut cur_data = self.data.write().await;
+        // a stopped session leaves the node as it is, like never synced,
+        // checked again after the query, which waits for the other nodes
+        if op.session.halt() {
+            return Ok(cur_data.status);
+        }
         let (remote_data, remote_is_dir) = op.query_data(&self.path).await?;
+        if op.session.halt() {
+            return Ok(cur_data.status);
+        }
+        op.session.visit(&self.path);
         let np_wd = cur_data.wd.clone().or(p_wd.clone());
 
+        // neither existing nor deleted on a partial replica
+        if remote_data.holding == Holding::Unheld {
+            SyncBanner::skip_unheld(&self.path);
+            op.report
+                .record(&self.path, SyncAction::Skipped, "not held by the remote");
+            return Ok(cur_data.status);
+        }
+
         if cur_data.status.deleted() && remote_data.status.deleted() {
             SyncBanner::skip_both_deleted(&self.path);
+            op.report
+                .record(&self.path, SyncAction::Skipped, "both deleted");
             return Ok(cur_data.status);
         }
 
         if remote_data.status.exist() && remote_data.mod_time.leq(&cur_data.sync_time) {
             SyncBanner::skip_newer(&self.path);
+            op.report.record(&self.path, SyncAction::Skipped, "newer");
             return Ok(cur_data.status);
         }
 
@@ -465,8 +695,13 @@ impl Node {
             && remote_data.status.exist()
             && self.path.is_dir() != remote_is_dir
         {
-            SyncBanner::skip_different_type(&self.path);
-            return Ok(cur_data.status);
+            let kept = self
+                .resolve_type(&op, &mut cur_data, &remote_data, remote_is_dir, p_wd)
+                .await?;
+            if kept {
+                return Ok(cur_data.status);
+            }
+            // the local one is gone, the remote one is taken like a new one
         }
 
         if (cur_data.status.exist() && !self.path.is_dir())
@@ -481,6 +716,8 @@ impl Node {
         name_list.append(&mut remote_data.children.clone());
         name_list.sort();
         name_list.dedup();
+        // the ignored files of local replica are never synchronized
+        name_list.retain(|name| !self.meta.is_ignored(&self.path.join_name(name)));
 
         let mut join_set = tokio::task::JoinSet::new();
 
@@ -491,7 +728,7 @@ impl Node {
             join_set.spawn(async move {
                 let res = child.sync_node(op, &np_wd).await?;
                 // return the Arc<Node> in case that the tmp child is lost
-                MyResult::Ok((res, child))
+                TraResult::Ok((res, child))
             });
         }
 
@@ -503,39 +740,75 @@ impl Node {
         // join all the child threads
         let mut have_any_child_exist = NodeStatus::Deleted;
         while let Some(res) = join_set.join_next().await {
-            let (res, child) = res.or::<String>(Err("Sync Node : thread join error".into()))??;
+            let (res, child) =
+                res.map_err(|e| TraError::internal("Sync Node", e).at(&self.path))??;
             if res.exist() {
-                cur_data.children.insert(child.file_name(), child);
+                if !op.dry_run {
+                    cur_data.children.insert(child.file_name(), child);
+                }
                 have_any_child_exist.set_exist();
             }
         }
 
-        cur_data.pushup_mod().await;
-        cur_data.sync_time = remote_data.sync_time.clone();
-        cur_data.sync_time.update_one(self.meta.id, op.time);
+        // a planned dir keeps its mod time, which already covers the children
+        // the sync time of a partially held dir says nothing about the unheld children,
+        // nor about the children aborted or left by a stopped session
+        let halted = op.session.halted().is_some();
+        let incomplete = remote_data.holding == Holding::Partial
+            || self.meta.holding(&self.path) == Holding::Partial
+            || op.report.aborted_under(&self.path)
+            || halted;
+        if !op.dry_run {
+            cur_data.pushup_mod().await;
+            if !incomplete {
+                cur_data.sync_time = remote_data.sync_time.clone();
+            }
+            cur_data.sync_time.update_one(self.meta.id, op.time);
+        }
+
+        if halted && (cur_data.status.exist() || have_any_child_exist.deleted()) {
+            // some children may be left, the dir is deleted or created by the next sync
+            return Ok(cur_data.status);
+        }
 
         if remote_data.status.deleted() && cur_data.mod_time.leq(&remote_data.sync_time) {
             assert!(have_any_child_exist.deleted());
             SyncBanner::delete(&self.path);
+            op.report.record(&self.path, SyncAction::Deleted, "dir");
+            if op.dry_run {
+                return Ok(NodeStatus::Deleted);
+            }
 
             cur_data.status.set_deleted();
 
-            let wd = cur_data.wd.take().unwrap();
-            self.meta
-                .watch
-                .remove_watch(self.path.as_ref(), &wd)
-                .await?;
+            if let Some(wd) = cur_data.wd.take() {
+                self.meta
+                    .watch
+                    .remove_watch(self.path.as_ref(), &wd)
+                    .await?;
+            } else {
+                self.meta.watch.remove_polling(&self.path).await;
+            }
 
-            self.meta.watch.freeze_watch(&p_wd.clone().unwrap()).await;
-            delete_empty_dir(&self.path).await?;
+            self.meta.watch.freeze_watch(p_wd).await;
+            let res = delete_empty_dir(&self.path).await;
+            self.meta.watch.refresh_polling(&self.path).await;
+            self.meta.watch.unfreeze_watch(p_wd).await;
+            res?;
         } else if cur_data.status.deleted() {
             SyncBanner::create_to_independent_empty(&self.path);
+            op.report.record(&self.path, SyncAction::Created, "dir");
+            if op.dry_run {
+                return Ok(NodeStatus::Exist);
+            }
 
             // the dir may not be created, due to the folder is empty (contains no file but only subfolders)
             if !self.path.exists() {
-                self.meta.watch.freeze_watch(&p_wd.clone().unwrap()).await;
-                create_dir_all(&self.path).await?;
-                self.meta.watch.unfreeze_watch(&p_wd.clone().unwrap()).await;
+                self.meta.watch.freeze_watch(p_wd).await;
+                let res = create_dir_all(&self.path).await;
+                self.meta.watch.refresh_polling(&self.path).await;
+                self.meta.watch.unfreeze_watch(p_wd).await;
+                res?;
             }
 
             cur_data.status.set_exist();
@@ -546,28 +819,59 @@ impl Node {
         return Ok(cur_data.status);
     }
 
-    // sync a single remove file to local
+    // sync a single remove file to local, returns the status after the sync
     pub async fn sync_file(
         &self,
-        op: SyncOption,
+        mut op: SyncOption,
         cur_data: &mut RwLockWriteGuard<'_, NodeData>,
         remote_data: &RemoteData,
         p_wd: &Option<WatchDescriptor>,
-    ) -> MyResult<NodeStatus> {
+    ) -> TraResult<NodeStatus> {
+        // the watcher waits for this node, so an edit made since the last event
+        // is only seen on disk, deciding on the stale version would lose it
+        if !op.dry_run && self.changed_on_disk(cur_data).await {
+            self.record_unseen(cur_data, op.time).await?;
+            let err = TraError::aborted("Sync File", "changed locally, not recorded yet");
+            return Ok(self.abort_node(&op, cur_data.status, &err));
+        }
         if cur_data.status.exist() && remote_data.status.exist() {
             // both exist
             if cur_data.mod_time.leq(&remote_data.sync_time) {
                 // local_m <= remote_s SyncBanner::overwrite(&self.path);
                 SyncBanner::overwrite(&self.path);
-                self.sync_work(SyncType::Override, op, cur_data, &remote_data, p_wd)
-                    .await?;
+                return self
+                    .sync_work(SyncType::Override, op, cur_data, &remote_data, p_wd)
+                    .await;
             } else if remote_data.mod_time.leq(&cur_data.sync_time) {
                 // local_s >= remote_m
                 SyncBanner::skip_newer(&self.path);
+                op.report.record(&self.path, SyncAction::Skipped, "newer");
+            } else if self.same_content(&mut op, cur_data, remote_data).await? {
+                // the same change made on both, like seeded from the same checkout
+                SyncBanner::skip_same_content(&self.path);
+                let detail = match op.join {
+                    true => "same content, joined",
+                    false => "same content",
+                };
+                op.report.record(&self.path, SyncAction::Skipped, detail);
+                if !op.dry_run {
+                    if op.join {
+                        // the seeded copy is the peer's version, never a change of its own
+                        cur_data.mod_time = remote_data.mod_time.clone();
+                        cur_data.create_time = remote_data.create_time;
+                    } else {
+                        cur_data.mod_time.check_max(&remote_data.mod_time);
+                    }
+                    cur_data.sync_time.check_max(&remote_data.sync_time);
+                    cur_data.sync_time.update_one(self.meta.id, op.time);
+                }
+            } else if op.join && self.seeded(cur_data) {
+                SyncBanner::overwrite(&self.path);
+                return self
+                    .sync_work(SyncType::Override, op, cur_data, remote_data, p_wd)
+                    .await;
             } else {
                 // report conflicts
-                let _guard = self.meta.c_lock.lock().await;
-                SyncBanner::conflict(&self.path);
                 self.sync_conflicts(op, cur_data, remote_data, p_wd).await?;
             }
         } else if cur_data.status.exist() || remote_data.status.exist() {
@@ -576,38 +880,61 @@ impl Node {
                 if cur_data.create_time.leq_vec(&remote_data.sync_time) {
                     if cur_data.mod_time.leq(&remote_data.sync_time) {
                         SyncBanner::delete(&self.path);
-                        self.sync_work(SyncType::Delete, op, cur_data, remote_data, p_wd)
-                            .await?;
+                        return self
+                            .sync_work(SyncType::Delete, op, cur_data, remote_data, p_wd)
+                            .await;
                     } else {
-                        let _guard = self.meta.c_lock.lock().await;
-                        SyncBanner::conflict(&self.path);
                         self.sync_conflicts(op, cur_data, remote_data, p_wd).await?;
                     }
                 } else {
                     SyncBanner::skip_from_independent_empty(&self.path);
+                    op.report
+                        .record(&self.path, SyncAction::Skipped, "created independently");
                 }
             } else {
                 // remote -> local(deleted)
                 if remote_data.create_time.leq_vec(&cur_data.sync_time) {
                     if remote_data.mod_time.leq(&cur_data.sync_time) {
                         SyncBanner::skip_newer(&self.path);
+                        op.report.record(&self.path, SyncAction::Skipped, "newer");
                     } else {
-                        let _guard = self.meta.c_lock.lock().await;
-                        SyncBanner::conflict(&self.path);
                         self.sync_conflicts(op, cur_data, remote_data, p_wd).await?;
                     }
                 } else {
                     SyncBanner::create_to_independent_empty(&self.path);
-                    self.sync_work(SyncType::Create, op, cur_data, &remote_data, p_wd)
-                        .await?;
+                    return self
+                        .sync_work(SyncType::Create, op, cur_data, &remote_data, p_wd)
+                        .await;
                 }
             }
         } else {
             SyncBanner::skip_both_deleted(&self.path);
+            op.report
+                .record(&self.path, SyncAction::Skipped, "both deleted");
         }
         return Ok(cur_data.status);
     }
 
+    // false if either side changed since its version was decided on
+    async fn same_content(
+        &self,
+        op: &mut SyncOption,
+        cur_data: &NodeData,
+        remote_data: &RemoteData,
+    ) -> TraResult<bool> {
+        let remote = op.query_hash(&self.path).await?;
+        let unchanged = remote.mod_time.leq(&remote_data.mod_time)
+            && remote_data.mod_time.leq(&remote.mod_time);
+        if !unchanged || remote.hash.is_empty() {
+            return Ok(false);
+        }
+        let data = read_bytes(&self.path).await?;
+        if file_stamp(&self.path).await != cur_data.stamp {
+            return Ok(false);
+        }
+        Ok(content_hash(&data) == remote.hash)
+    }
+
     pub async fn sync_work(
         &self,
         ty: SyncType,
@@ -615,19 +942,40 @@ impl Node {
         cur_data: &mut RwLockWriteGuard<'_, NodeData>,
         remote_data: &RemoteData,
         p_wd: &Option<WatchDescriptor>,
-    ) -> MyResult<()> {
+    ) -> TraResult<NodeStatus> {
         assert!(cur_data.wd.is_none());
-        let wd = p_wd.clone().expect("Sync Work : wd is none");
-        self.meta.watch.freeze_watch(&wd).await;
-        match ty {
+        if op.dry_run {
+            return Ok(self.plan_work(ty, &op));
+        }
+        self.meta.watch.freeze_watch(p_wd).await;
+        let res = match ty {
             SyncType::Create | SyncType::Override => {
-                sync_bytes(&self.path, op.client).await?;
+                let action = match ty {
+                    SyncType::Create => SyncAction::Created,
+                    _ => SyncAction::Overwritten,
+                };
+                sync_bytes(&self.path, op.clone(), &remote_data.mod_time)
+                    .await
+                    .map(|(transfer, stamp)| {
+                        op.report
+                            .record_transfer(&self.path, action, "file", transfer);
+                        op.session.transferred(&self.path, transfer.delta_bytes);
+                        stamp
+                    })
             }
-            SyncType::Delete => {
-                delete_file(&self.path).await?;
+            SyncType::Delete => delete_file(&self.path).await.map(|_| {
+                op.report.record(&self.path, SyncAction::Deleted, "file");
+                None
+            }),
+        };
+        self.meta.watch.refresh_polling(&self.path).await;
+        self.meta.watch.unfreeze_watch(p_wd).await;
+        cur_data.stamp = match res {
+            Err(e) if e.kind == ErrorKind::Aborted => {
+                return Ok(self.abort_node(&op, cur_data.status, &e));
             }
-        }
-        self.meta.watch.unfreeze_watch(&wd).await;
+            res => res?,
+        };
 
         cur_data.mod_time = remote_data.mod_time.clone();
         cur_data.sync_time = remote_data.sync_time.clone();
@@ -645,16 +993,263 @@ impl Node {
             SyncType::Delete => cur_data.status.set_deleted(),
         }
 
+        Ok(cur_data.status)
+    }
+
+    // a file on one side and a dir on the other, returns whether the local one is kept,
+    // otherwise the local one is removed or moved aside, and the node is left deleted
+    // with no sync time, so that the remote one is created over it
+    async fn resolve_type(
+        &self,
+        op: &SyncOption,
+        cur_data: &mut RwLockWriteGuard<'_, NodeData>,
+        remote_data: &RemoteData,
+        remote_is_dir: bool,
+        p_wd: &Option<WatchDescriptor>,
+    ) -> TraResult<bool> {
+        let (local_ty, remote_ty) = match remote_is_dir {
+            true => ("file", "dir"),
+            false => ("dir", "file"),
+        };
+        let seeded = op.join && self.seeded(cur_data);
+        let resolution = if seeded || cur_data.mod_time.leq(&remote_data.sync_time) {
+            SyncBanner::replace_type(&self.path, remote_ty);
+            if op.dry_run {
+                let detail = format!("{} over the {}", remote_ty, local_ty);
+                op.report
+                    .record(&self.path, SyncAction::Overwritten, &detail);
+                return Ok(true);
+            }
+            TypeResolution::Replace
+        } else if self.left_unresolved(op) {
+            op.report.record(
+                &self.path,
+                SyncAction::Conflict,
+                "unresolved, different type",
+            );
+            return Ok(true);
+        } else {
+            op.session.conflict_pending();
+            let resolution = {
+                let _guard = self.meta.c_lock.lock().await;
+                SyncBanner::conflict(&self.path);
+                self.select_type(op.decided(&self.path), local_ty, remote_ty)
+            };
+            op.session.conflict_resolved();
+            resolution
+        };
+
+        if resolution == TypeResolution::Local {
+            op.report.record(&self.path, SyncAction::Conflict, "local");
+            cur_data.sync_time.check_max(&remote_data.sync_time);
+            cur_data.sync_time.update_one(self.meta.id, op.time);
+            return Ok(true);
+        }
+
+        if self.path.is_dir() {
+            self.forget_watches(cur_data).await?;
+        }
+        self.meta.watch.freeze_watch(p_wd).await;
+        let res = match resolution {
+            TypeResolution::Aside => self.move_aside().await.map(|aside| {
+                let detail = format!("remote, the local {} moved aside", local_ty);
+                op.report.record(&self.path, SyncAction::Conflict, &detail);
+                op.report.aside(aside);
+            }),
+            _ if self.path.is_dir() => tokio::fs::remove_dir_all(&self.path)
+                .await
+                .map_err(|e| TraError::io("Replace Type", &self.path, e)),
+            _ => delete_file(&self.path).await,
+        };
+        self.meta.watch.refresh_polling(&self.path).await;
+        self.meta.watch.unfreeze_watch(p_wd).await;
+        res?;
+        match resolution {
+            TypeResolution::Remote => {
+                let detail = format!("remote, the local {} replaced", local_ty);
+                op.report.record(&self.path, SyncAction::Conflict, &detail);
+            }
+            TypeResolution::Replace => {
+                let detail = format!("{} over the {}", remote_ty, local_ty);
+                op.report
+                    .record(&self.path, SyncAction::Overwritten, &detail);
+            }
+            _ => {}
+        }
+
+        cur_data.children.clear();
+        cur_data.status.set_deleted();
+        cur_data.sync_time = VectorTime::default();
+        cur_data.stamp = None;
+        Ok(false)
+    }
+
+    // a plan never prompts, nor a sync with nobody to answer the prompt
+    fn left_unresolved(&self, op: &SyncOption) -> bool {
+        let prompt =
+            self.meta.conflict == ConflictPolicy::Prompt && op.decided(&self.path).is_none();
+        op.dry_run || (op.unattended && prompt)
+    }
+
+    fn select_type(
+        &self,
+        decided: Option<bool>,
+        local_ty: &str,
+        remote_ty: &str,
+    ) -> TypeResolution {
+        let choices = [
+            format!("keep the local {}", local_ty),
+            format!("take the remote {}", remote_ty),
+            format!(
+                "move the local {} aside, take the remote {}",
+                local_ty, remote_ty
+            ),
+        ];
+        let selection = match (decided, self.meta.conflict) {
+            (Some(take_remote), _) => take_remote as usize,
+            (None, ConflictPolicy::Prompt) => Select::with_theme(&ColorfulTheme::default())
+                .with_prompt(" A file and a dir conflict, please choose a resolution:")
+                .items(&choices)
+                .default(0)
+                .interact()
+                .unwrap(),
+            (None, ConflictPolicy::Local) => 0,
+            (None, ConflictPolicy::Remote) => 1,
+        };
+        if decided.is_some() || self.meta.conflict != ConflictPolicy::Prompt {
+            SyncBanner::auto_resolve(&self.path, &choices[selection]);
+        }
+        match selection {
+            0 => TypeResolution::Local,
+            1 => TypeResolution::Remote,
+            _ => TypeResolution::Aside,
+        }
+    }
+
+    // the watches of a dir and its descendants, before the dir leaves the tree
+    #[async_recursion]
+    async fn forget_watches(&self, data: &mut NodeData) -> TraResult<()> {
+        if let Some(wd) = data.wd.take() {
+            self.meta
+                .watch
+                .remove_watch(self.path.as_ref(), &wd)
+                .await?;
+        } else {
+            self.meta.watch.remove_polling(&self.path).await;
+        }
+        for child in data.children.values() {
+            let mut child_data = child.data.write().await;
+            child.forget_watches(&mut child_data).await?;
+        }
         Ok(())
     }
 
+    // "<name>.conflict-<id>", numbered if taken, recorded as a local creation after the sync
+    async fn move_aside(&self) -> TraResult<PathLocal> {
+        let mut parent = self.path.clone();
+        let name = parent.pop().unwrap_or_default();
+        let mut aside = parent.join_name(format!("{}.conflict-{}", name, self.meta.id));
+        let mut n = 1;
+        while aside.exists() {
+            n += 1;
+            aside = parent.join_name(format!("{}.conflict-{}-{}", name, self.meta.id, n));
+        }
+        tokio::fs::rename(&self.path, &aside)
+            .await
+            .map_err(|e| TraError::io("Move Aside", &self.path, e))?;
+        Ok(aside)
+    }
+
+    // a file changed during its sync keeps the recorded version,
+    // the next sync decides again with the change recorded
+    fn abort_node(&self, op: &SyncOption, status: NodeStatus, err: &TraError) -> NodeStatus {
+        SyncBanner::aborted(&self.path, err);
+        op.report
+            .record(&self.path, SyncAction::Aborted, &err.cause);
+        status
+    }
+
+    // the edits made while the watch of the parent was frozen never arrive as events,
+    // recorded now like a local one, so that only this sync is aborted for them
+    async fn record_unseen(&self, data: &mut NodeData, time: i32) -> TraResult<()> {
+        LocalBanner::unseen(&self.path);
+        data.mod_time.update_one(self.meta.id, time);
+        data.sync_time.update_one(self.meta.id, time);
+        data.stamp = file_stamp(&self.path).await;
+        if !self.path.exists() {
+            data.status.set_deleted();
+            data.children.clear();
+            return Ok(());
+        }
+        if data.status.deleted() {
+            data.status.set_exist();
+            data.create_time = SingletonTime::new(self.meta.id, time);
+        }
+        if self.path.is_dir() && data.wd.is_none() {
+            data.wd = self.meta.watch.add_watch(&self.path).await;
+            self.scan_data(data, time).await?;
+        }
+        Ok(())
+    }
+
+    async fn changed_on_disk(&self, data: &NodeData) -> bool {
+        match data.status {
+            NodeStatus::Exist => file_stamp(&self.path).await != data.stamp,
+            NodeStatus::Deleted => self.path.exists(),
+        }
+    }
+
+    // the transfer is unknown until the patch is fetched
+    fn plan_work(&self, ty: SyncType, op: &SyncOption) -> NodeStatus {
+        match ty {
+            SyncType::Create => {
+                op.report.record(&self.path, SyncAction::Created, "file");
+                NodeStatus::Exist
+            }
+            SyncType::Override => {
+                op.report
+                    .record(&self.path, SyncAction::Overwritten, "file");
+                NodeStatus::Exist
+            }
+            SyncType::Delete => {
+                op.report.record(&self.path, SyncAction::Deleted, "file");
+                NodeStatus::Deleted
+            }
+        }
+    }
+
     pub async fn sync_conflicts(
         &self,
         op: SyncOption,
         cur_data: &mut RwLockWriteGuard<'_, NodeData>,
         remote_data: &RemoteData,
         p_wd: &Option<WatchDescriptor>,
-    ) -> MyResult<()> {
+    ) -> TraResult<()> {
+        // the conflicts are resolved one by one, each with its own prompt
+        op.session.conflict_pending();
+        let res = {
+            let _guard = self.meta.c_lock.lock().await;
+            SyncBanner::conflict(&self.path);
+            self.resolve_conflict(op.clone(), cur_data, remote_data, p_wd)
+                .await
+        };
+        op.session.conflict_resolved();
+        res
+    }
+
+    async fn resolve_conflict(
+        &self,
+        op: SyncOption,
+        cur_data: &mut RwLockWriteGuard<'_, NodeData>,
+        remote_data: &RemoteData,
+        p_wd: &Option<WatchDescriptor>,
+    ) -> TraResult<()> {
+        if self.left_unresolved(&op) {
+            // the local version stays until a sync which can decide
+            op.report
+                .record(&self.path, SyncAction::Conflict, "unresolved");
+            return Ok(());
+        }
         let choices = &mut [
             "use the local version".to_string(),
             "use the remote version".to_string(),
@@ -667,26 +1262,61 @@ impl Node {
             choices[1].push_str("(deleted)");
         }
 
-        let selection = Select::with_theme(&ColorfulTheme::default())
-            .with_prompt(" Conflict detected, please choose a resolution:")
-            .items(choices)
-            .default(0)
-            .interact()
-            .unwrap();
+        let decided = op.decided(&self.path);
+        let selection = match (decided, self.meta.conflict) {
+            (Some(take_remote), _) => take_remote as usize,
+            (None, ConflictPolicy::Prompt) => Select::with_theme(&ColorfulTheme::default())
+                .with_prompt(" Conflict detected, please choose a resolution:")
+                .items(choices)
+                .default(0)
+                .interact()
+                .unwrap(),
+            (None, ConflictPolicy::Local) => 0,
+            (None, ConflictPolicy::Remote) => 1,
+        };
+        if decided.is_some() || self.meta.conflict != ConflictPolicy::Prompt {
+            SyncBanner::auto_resolve(&self.path, &choices[selection]);
+        }
 
         // the conflicted should be a file instead of a dir
         assert!(cur_data.wd.is_none());
         assert!(cur_data.children.is_empty());
-        let wd = p_wd.clone().expect("Sync Conflicts : wd is none");
-        self.meta.watch.freeze_watch(&wd).await;
+        self.meta.watch.freeze_watch(p_wd).await;
+        let res = self
+            .apply_resolution(selection, op, cur_data, remote_data)
+            .await;
+        self.meta.watch.refresh_polling(&self.path).await;
+        self.meta.watch.unfreeze_watch(p_wd).await;
+        res
+    }
 
+    // under the frozen watch, which is released whatever the result
+    async fn apply_resolution(
+        &self,
+        selection: usize,
+        op: SyncOption,
+        cur_data: &mut RwLockWriteGuard<'_, NodeData>,
+        remote_data: &RemoteData,
+    ) -> TraResult<()> {
         match selection {
             0 => {
+                op.report.record(&self.path, SyncAction::Conflict, "local");
                 cur_data.sync_time = remote_data.sync_time.clone();
                 cur_data.sync_time.update_one(self.meta.id, op.time);
             }
             1 => {
-                sync_bytes(&self.path, op.client).await?;
+                let res = sync_bytes(&self.path, op.clone(), &remote_data.mod_time).await;
+                let (transfer, stamp) = match res {
+                    Err(e) if e.kind == ErrorKind::Aborted => {
+                        self.abort_node(&op, cur_data.status, &e);
+                        return Ok(());
+                    }
+                    res => res?,
+                };
+                op.report
+                    .record_transfer(&self.path, SyncAction::Conflict, "remote", transfer);
+                op.session.transferred(&self.path, transfer.delta_bytes);
+                cur_data.stamp = stamp;
                 cur_data.sync_time = remote_data.sync_time.clone();
                 cur_data.sync_time.update_one(self.meta.id, op.time);
                 // use the remote version, pass all the infos to local
@@ -695,11 +1325,16 @@ impl Node {
                 cur_data.status = remote_data.status.clone();
             }
             2 => {
-                let success = manually_resolve(&self.path, op.clone()).await?;
+                let success =
+                    manually_resolve(&self.path, op.clone(), &remote_data.mod_time).await?;
+                let resolution = if success { "manual" } else { "manual, aborted" };
+                op.report
+                    .record(&self.path, SyncAction::Conflict, resolution);
                 if success {
                     cur_data.sync_time = remote_data.sync_time.clone();
                     cur_data.sync_time.update_one(self.meta.id, op.time);
                     cur_data.mod_time.update_one(self.meta.id, op.time);
+                    cur_data.stamp = file_stamp(&self.path).await;
                     if cur_data.status.deleted() {
                         cur_data.status.set_exist();
                         cur_data.create_time = SingletonTime::new(self.meta.id, op.time);
@@ -708,9 +1343,6 @@ impl Node {
             }
             _ => unreachable!(),
         }
-
-        self.meta.watch.unfreeze_watch(&wd).await;
-
         Ok(())
     }
 }
@@ -3,7 +3,10 @@ use crate::{
     timestamp::{SingletonTime, VectorTime},
 };
 
-use super::node::{NodeData, NodeStatus};
+use super::{
+    meta::Holding,
+    node::{NodeData, NodeStatus},
+};
 
 pub struct RemoteData {
     pub children: Vec<String>,
@@ -11,6 +14,9 @@ pub struct RemoteData {
     pub sync_time: VectorTime,
     pub create_time: SingletonTime,
     pub status: NodeStatus,
+    pub holding: Holding,
+    // empty unless asked
+    pub hash: Vec<u8>,
 }
 
 impl QueryRes {
@@ -23,6 +29,9 @@ impl QueryRes {
             sync_time: data.sync_time.clone().into(),
             children: data.children.iter().map(|(k, _)| k.clone()).collect(),
             is_dir,
+            unheld: false,
+            partial: false,
+            hash: Vec::new(),
         }
     }
 
@@ -37,6 +46,12 @@ impl QueryRes {
             } else {
                 NodeStatus::Exist
             },
+            holding: match (self.unheld, self.partial) {
+                (true, _) => Holding::Unheld,
+                (false, true) => Holding::Partial,
+                (false, false) => Holding::Full,
+            },
+            hash: self.hash.clone(),
         };
         (data, self.is_dir)
     }
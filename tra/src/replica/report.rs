@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::{
+    checker::normal_path,
+    reptra::{SyncAction, SyncEntry, SyncReport},
+};
+
+use super::path_local::PathLocal;
+
+// the bytes fetched for one file
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Transfer {
+    pub delta_bytes: u64,
+    pub file_bytes: u64,
+}
+
+// shared by all the nodes of one sync request, the banners stay on the replica
+#[derive(Clone)]
+pub struct SyncRecorder {
+    entries: Arc<Mutex<Vec<SyncEntry>>>,
+    // the local ones moved aside by the conflicts, recorded after the sync
+    asides: Arc<Mutex<Vec<PathLocal>>>,
+    start: Instant,
+}
+
+impl Default for SyncRecorder {
+    fn default() -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(Vec::new())),
+            asides: Arc::new(Mutex::new(Vec::new())),
+            start: Instant::now(),
+        }
+    }
+}
+
+impl SyncRecorder {
+    pub fn record(&self, path: &PathLocal, action: SyncAction, detail: &str) {
+        self.record_transfer(path, action, detail, Transfer::default());
+    }
+
+    pub fn record_transfer(
+        &self,
+        path: &PathLocal,
+        action: SyncAction,
+        detail: &str,
+        transfer: Transfer,
+    ) {
+        let entry = SyncEntry {
+            path_rel: path.to_rel(),
+            action: action as i32,
+            detail: detail.to_string(),
+            delta_bytes: transfer.delta_bytes,
+            file_bytes: transfer.file_bytes,
+        };
+        self.entries.lock().unwrap().push(entry);
+    }
+
+    pub fn aside(&self, path: PathLocal) {
+        self.asides.lock().unwrap().push(path);
+    }
+
+    pub fn take_asides(&self) -> Vec<PathLocal> {
+        std::mem::take(&mut self.asides.lock().unwrap())
+    }
+
+    // whether a file under the path is aborted, then the dir still misses its remote version
+    pub fn aborted_under(&self, path: &PathLocal) -> bool {
+        let dir = normal_path(&path.to_rel());
+        self.entries.lock().unwrap().iter().any(|entry| {
+            entry.action == SyncAction::Aborted as i32
+                && normal_path(&entry.path_rel).starts_with(&dir)
+        })
+    }
+
+    pub fn finish(&self) -> SyncReport {
+        SyncReport {
+            entries: self.entries.lock().unwrap().clone(),
+            duration_ms: self.start.elapsed().as_millis() as u64,
+            dry_run: false,
+            session: String::new(),
+            stopped: String::new(),
+        }
+    }
+}
+
+impl SyncReport {
+    pub fn count(&self, action: SyncAction) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| entry.action == action as i32)
+            .count()
+    }
+
+    // the conflicts this side has resolved, by the path whether the other side
+    // takes this version when it pulls back, the unresolved ones are left out
+    pub fn decisions(&self) -> HashMap<String, bool> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.action == SyncAction::Conflict as i32)
+            .filter_map(|entry| {
+                let take_peer = match entry.detail.as_str() {
+                    "local" | "manual" => true,
+                    detail if detail.starts_with("remote") => false,
+                    _ => return None,
+                };
+                Some((entry.path_rel.clone(), take_peer))
+            })
+            .collect()
+    }
+
+    // the entries which are neither skipped nor aborted
+    pub fn changes(&self) -> usize {
+        self.entries.len() - self.count(SyncAction::Skipped) - self.count(SyncAction::Aborted)
+    }
+
+    pub fn delta_bytes(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.delta_bytes).sum()
+    }
+
+    pub fn file_bytes(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.file_bytes).sum()
+    }
+}
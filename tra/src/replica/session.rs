@@ -0,0 +1,193 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{TraError, TraResult},
+    reptra::Progress,
+};
+
+use super::path_local::PathLocal;
+
+// how often the progress of a session is sent
+pub const PROGRESS_TICK: Duration = Duration::from_millis(200);
+
+// a running sync, it stops at the next node once cancelled or past its deadline,
+// the nodes already synced keep their times, so the sync is safe to run again
+pub struct Session {
+    pub id: String,
+    // "<root>:<path>" pulled from the peer
+    pub target: String,
+    deadline: Option<Instant>,
+    cancelled: AtomicBool,
+    // why some nodes are left, set by the first node which stops
+    halted: OnceLock<&'static str>,
+    done: AtomicBool,
+    nodes: AtomicU64,
+    files: AtomicU64,
+    bytes: AtomicU64,
+    conflicts: AtomicU32,
+    path: Mutex<String>,
+}
+
+impl Session {
+    // a fresh id for the caller which has none
+    pub fn new_id() -> String {
+        format!("{:08x}", rand::random::<u32>())
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    // checked at each node boundary, true if the node should be left as it is
+    pub fn halt(&self) -> bool {
+        let reason = if self.cancelled.load(Ordering::Relaxed) {
+            "cancelled"
+        } else if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            "timed out"
+        } else {
+            return false;
+        };
+        let _ = self.halted.set(reason);
+        true
+    }
+
+    // None if no node is left
+    pub fn halted(&self) -> Option<&'static str> {
+        self.halted.get().copied()
+    }
+
+    pub fn visit(&self, path: &PathLocal) {
+        self.nodes.fetch_add(1, Ordering::Relaxed);
+        *self.path.lock().unwrap() = path.to_rel();
+    }
+
+    pub fn transferred(&self, path: &PathLocal, bytes: u64) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        *self.path.lock().unwrap() = path.to_rel();
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // a conflict waits for the lock and the prompt
+    pub fn conflict_pending(&self) {
+        self.conflicts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn conflict_resolved(&self) {
+        self.conflicts.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress {
+            nodes: self.nodes.load(Ordering::Relaxed),
+            files: self.files.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            conflicts: self.conflicts.load(Ordering::Relaxed),
+            path: self.path.lock().unwrap().clone(),
+            done: self.done.load(Ordering::Relaxed),
+        }
+    }
+
+    // a dropped request only cancels its sync, which still stops at a node boundary
+    pub fn cancel_on_drop(self: &Arc<Self>) -> CancelOnDrop {
+        CancelOnDrop(self.clone())
+    }
+}
+
+pub struct CancelOnDrop(Arc<Session>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+// the running syncs of a replica, by session id
+#[derive(Default)]
+pub struct Sessions {
+    running: Mutex<BTreeMap<String, Arc<Session>>>,
+}
+
+impl Sessions {
+    pub fn start(
+        &self,
+        id: String,
+        target: String,
+        timeout: Option<Duration>,
+    ) -> TraResult<Arc<Session>> {
+        let mut running = self.running.lock().unwrap();
+        if running.contains_key(&id) {
+            return Err(TraError::invalid(
+                "Sync Session",
+                format!("session {} is already running", id),
+            ));
+        }
+        let session = Arc::new(Session {
+            id: id.clone(),
+            target,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            cancelled: AtomicBool::new(false),
+            halted: OnceLock::new(),
+            done: AtomicBool::new(false),
+            nodes: AtomicU64::new(0),
+            files: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            conflicts: AtomicU32::new(0),
+            path: Mutex::new(String::new()),
+        });
+        running.insert(id, session.clone());
+        Ok(session)
+    }
+
+    pub fn finish(&self, id: &str) {
+        if let Some(session) = self.running.lock().unwrap().remove(id) {
+            session.done.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // the progress may be asked for right after the sync is requested
+    pub async fn wait_for(&self, id: &str) -> TraResult<Arc<Session>> {
+        for _ in 0..20 {
+            if let Some(session) = self.running.lock().unwrap().get(id) {
+                return Ok(session.clone());
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        Err(TraError::not_found(
+            "Sync Progress",
+            format!("no running session {}", id),
+        ))
+    }
+
+    pub fn cancel(&self, id: &str) -> TraResult<()> {
+        match self.running.lock().unwrap().get(id) {
+            Some(session) => {
+                session.cancel();
+                Ok(())
+            }
+            None => Err(TraError::not_found(
+                "Cancel Sync",
+                format!("no running session {}", id),
+            )),
+        }
+    }
+
+    // "<id> <target>" of each running session
+    pub fn list(&self) -> Vec<String> {
+        self.running
+            .lock()
+            .unwrap()
+            .values()
+            .map(|session| format!("{} {}", session.id, session.target))
+            .collect()
+    }
+}
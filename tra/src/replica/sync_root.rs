@@ -0,0 +1,277 @@
+use std::{
+    path::{Component, Path},
+    sync::Arc,
+};
+
+use tokio::sync::{Mutex, RwLock};
+
+use crate::{
+    config::{MpscSender, ReplicaConfig, RpcChannel},
+    error::{ErrorKind, TraError, TraResult},
+    reptra::{notifier::Change, QueryRes, RsyncClient, SyncParams, SyncReport},
+    timestamp::{SingletonTime, VectorTime},
+};
+
+use super::{
+    file_watcher::WatchIfc,
+    meta::{content_hash, file_stamp, read_bytes, FileStamp, Holding, Meta},
+    node::{ModOption, ModType, Node, SyncOption},
+    path_local::PathLocal,
+    report::SyncRecorder,
+    session::Session,
+};
+
+// the first event of a root is the scan of `init_all`
+pub const INIT_TIME: i32 = 1;
+
+// an independent directory tree synchronized under its name,
+// with its own counter so that the vector times of different roots never mix
+pub struct SyncRoot {
+    pub meta: Arc<Meta>,
+    pub counter: RwLock<i32>,
+    pub base_node: Arc<Node>,
+}
+
+impl SyncRoot {
+    pub async fn read_counter(&self) -> i32 {
+        *self.counter.read().await
+    }
+
+    pub async fn add_counter(&self) -> i32 {
+        let mut now = self.counter.write().await;
+        *now += 1;
+        *now
+    }
+
+    pub async fn new(
+        conf: &ReplicaConfig,
+        name: &str,
+        root: impl AsRef<Path>,
+        watch: WatchIfc,
+        c_lock: Arc<Mutex<()>>,
+        changes: MpscSender<Change>,
+    ) -> Self {
+        let meta = Arc::new(Meta::new(conf, name, watch, c_lock, changes));
+        let path = PathLocal::new_from_rel(root, "");
+        if !path.exists() {
+            tokio::fs::create_dir_all(&path).await.unwrap();
+        } else if !path.is_dir() {
+            panic!("The root path is not a directory!");
+        }
+        let base_node = Node::new_base_node(&meta, path).await;
+        let base_node = Arc::new(base_node);
+        Self {
+            meta,
+            counter: RwLock::new(0),
+            base_node,
+        }
+    }
+
+    pub async fn init_all(&self) -> TraResult<()> {
+        // init the whole file tree, all inintial is in time 1
+        let init_counter = self.add_counter().await;
+        debug_assert_eq!(init_counter, INIT_TIME);
+        self.base_node.scan_all(init_counter).await?;
+        self.base_node
+            .data
+            .write()
+            .await
+            .mod_time
+            .check_max(&VectorTime::from_singleton_time(&SingletonTime::new(
+                self.meta.id,
+                init_counter,
+            )));
+        Ok(())
+    }
+
+    // the events are lost, so the disk is compared with the tree again
+    pub async fn rescan(&self) -> TraResult<()> {
+        let time = self.add_counter().await;
+        self.base_node.scan_all(time).await?;
+        let mut stale = Vec::new();
+        self.base_node.stale_changes(&mut stale).await;
+        for (dir, name, ty, is_dir) in stale {
+            self.handle_local(&dir, name, ty, is_dir).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn tree(&self, show_detail: bool) {
+        self.base_node.sub_tree(show_detail, Vec::new()).await;
+    }
+
+    // whether the local path belongs to this root
+    pub fn contains(&self, path: &PathLocal) -> bool {
+        path.prefix() == self.base_node.path.prefix()
+    }
+}
+
+impl SyncRoot {
+    // a local modification from the watcher or the poller
+    pub async fn handle_local(
+        &self,
+        dir: &PathLocal,
+        name: String,
+        ty: ModType,
+        is_dir: bool,
+    ) -> TraResult<()> {
+        let path = dir.join_name(&name);
+        if self.meta.is_ignored(&path) {
+            return Ok(());
+        }
+        let walk = dir.get_walk();
+        let op = ModOption {
+            ty,
+            time: self.add_counter().await,
+            name,
+            is_dir,
+        };
+        self.base_node.handle_modify(walk, op).await?;
+        self.meta.changed(&path).await;
+        Ok(())
+    }
+
+    pub async fn handle_query(&self, path: &str, hash: bool) -> TraResult<QueryRes> {
+        let path = self.request_path("Query", path)?;
+        let holding = self.meta.holding(&path);
+        if holding == Holding::Unheld {
+            return Ok(QueryRes {
+                unheld: true,
+                ..Default::default()
+            });
+        }
+        let walk = path.get_walk();
+        let mut ret = self.base_node.handle_query(walk).await?;
+        ret.partial = holding == Holding::Partial;
+        if hash && !ret.deleted && !ret.is_dir {
+            // a version changed since recorded has no hash, the asker falls back to a conflict
+            ret.hash = match self
+                .read_unchanged(&path, &ret.mod_time.clone().into())
+                .await
+            {
+                Ok(data) => content_hash(&data),
+                Err(e) if e.kind == ErrorKind::Aborted => Vec::new(),
+                Err(e) => return Err(e),
+            };
+        }
+        ret.sync_time
+            .insert(self.meta.id, self.read_counter().await);
+        Ok(ret)
+    }
+
+    pub async fn handle_sync(
+        &self,
+        path: &str,
+        client: RsyncClient<RpcChannel>,
+        params: &SyncParams,
+        session: Arc<Session>,
+    ) -> TraResult<SyncReport> {
+        let (dry_run, join) = (params.dry_run, params.join);
+        let path = self.request_path("Sync", path)?;
+        if self.meta.is_ignored(&path) {
+            return Err(
+                TraError::precondition("Sync", "the path is ignored or not held").at(&path),
+            );
+        }
+        // only a replica still as seeded takes the peer's version over its own
+        if join && !self.base_node.all_seeded().await {
+            return Err(TraError::precondition(
+                "Join",
+                "the root is changed since it was seeded, sync it instead",
+            ));
+        }
+        let walk = path.get_walk();
+        // a plan is not an event of this replica
+        let time = match dry_run {
+            true => self.read_counter().await,
+            false => self.add_counter().await,
+        };
+        let op = SyncOption {
+            time,
+            root: self.meta.root.clone(),
+            client,
+            report: SyncRecorder::default(),
+            dry_run,
+            join,
+            decided: Arc::new(params.decided.clone()),
+            unattended: params.unattended,
+            session: session.clone(),
+        };
+        let res = self.base_node.handle_sync(op.clone(), walk, &None).await;
+        // no lock is held now, the moved aside ones are new to this replica
+        for mut aside in op.report.take_asides() {
+            let name = aside.pop().unwrap_or_default();
+            let is_dir = aside.join_name(&name).is_dir();
+            self.handle_local(&aside, name, ModType::Create, is_dir)
+                .await?;
+        }
+        res?;
+        Ok(SyncReport {
+            dry_run,
+            session: session.id.clone(),
+            stopped: session.halted().unwrap_or_default().to_string(),
+            ..op.report.finish()
+        })
+    }
+
+    // the content for a peer's patch, only if it is still the version the peer has queried,
+    // an aborted read is tried again by the peer, then left for its next sync
+    pub async fn read_unchanged(
+        &self,
+        path: &PathLocal,
+        mod_time: &VectorTime,
+    ) -> TraResult<Vec<u8>> {
+        let stamp = self.recorded_stamp("Fetch Patch", path, mod_time).await?;
+        let data = read_bytes(path).await?;
+        check_stamp("Fetch Patch", path, &stamp).await?;
+        Ok(data)
+    }
+
+    // the stamp of the file, only if it is still the recorded version of mod_time
+    pub async fn recorded_stamp(
+        &self,
+        op: &'static str,
+        path: &PathLocal,
+        mod_time: &VectorTime,
+    ) -> TraResult<Option<FileStamp>> {
+        let (recorded, stamp) = self.base_node.handle_stamp(path.get_walk()).await?;
+        if !(recorded.leq(mod_time) && mod_time.leq(&recorded)) {
+            return Err(TraError::aborted(op, "changed on the remote since the query").at(path));
+        }
+        if file_stamp(path).await != stamp {
+            // no event came for it, so the next query sees the change recorded
+            let mut dir = path.clone();
+            let name = dir.pop().unwrap_or_default();
+            let ty = match path.exists() {
+                true => ModType::Modify,
+                false => ModType::Delete,
+            };
+            self.handle_local(&dir, name, ty, path.is_dir()).await?;
+            return Err(TraError::aborted(op, "changed on the remote, not recorded yet").at(path));
+        }
+        Ok(stamp)
+    }
+
+    // the paths from the peers never leave the root
+    pub fn request_path(&self, op: &'static str, path: &str) -> TraResult<PathLocal> {
+        let escaped = Path::new(path)
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+        if escaped {
+            return Err(TraError::invalid(op, "the path is outside of the root").at(path));
+        }
+        Ok(PathLocal::new_from_rel(self.base_node.path.prefix(), path))
+    }
+}
+
+// after a read, the file is still the one stamped before it
+pub async fn check_stamp(
+    op: &'static str,
+    path: &PathLocal,
+    stamp: &Option<FileStamp>,
+) -> TraResult<()> {
+    if file_stamp(path).await != *stamp {
+        return Err(TraError::aborted(op, "changed on the remote while being read").at(path));
+    }
+    Ok(())
+}
@@ -1,3 +1,4 @@
+pub mod notifier;
 pub mod peer_server;
 
 pub mod peer {
@@ -8,22 +9,25 @@ pub mod peer {
 use crate::{
     banner::BannerOut,
     centra::{GreeterClient, HelloRequest, PortCollectClient, PortNumber},
-    config::{ServiceHandle, CHANNEL_BUFFER_SIZE},
+    config::{config, ReplicaConfig, RpcChannel, ServiceHandle},
+    error::{TraError, TraResult},
     machine::{channel_connect, get_listener, ServeAddr},
     replica::{file_watcher::FileWatcher, Replica},
-    MyResult,
 };
 
 use inotify::EventMask;
+use notifier::Notifier;
 use peer_server::PeerServer;
-use std::{cell::RefCell, collections::HashMap, sync::Arc};
-use tokio::sync::{Mutex, RwLock};
+use std::{cell::RefCell, collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::{mpsc, Mutex, RwLock};
 use tonic::{transport::Server, Request};
 
 pub use peer::{
     rsync_client::RsyncClient,
     rsync_server::{Rsync, RsyncServer},
-    FetchPatchReq, Patch, QueryReq, QueryRes, SyncReq, Void,
+    CancelReq, ChangeNote, CloneReq, FetchPatchReq, Patch, Progress, ProgressReq, QueryReq,
+    QueryRes, StatusRes, SubscribeReq, SyncAction, SyncEntry, SyncReport, SyncReq, TreeEntry,
+    TreeReq, Void,
 };
 
 pub struct Reptra {
@@ -35,17 +39,35 @@ pub struct Reptra {
 }
 
 impl Reptra {
-    pub async fn new_start_service(id: i32) -> MyResult<Self> {
-        let (serve_addr, incoming) = get_listener().await?;
+    pub async fn new_start_service(conf: &ReplicaConfig) -> TraResult<Self> {
+        let (serve_addr, incoming) = get_listener(&conf.host, conf.port).await?;
+        // a wildcard host can not be dialed from other machines
+        let serve_addr = match &conf.advertise {
+            Some(host) => ServeAddr::new(host, serve_addr.port()),
+            None => serve_addr,
+        };
         let file_watcher = FileWatcher::new();
         let watch = file_watcher.get_ifc();
         let c_lock = Arc::new(Mutex::new(()));
-        let replica = Arc::new(Replica::new(id, watch, c_lock).await);
+        let (changes_tx, changes_rx) = mpsc::channel(config().channel_buffer_size);
+        let replica = Arc::new(Replica::new(conf, watch, c_lock, changes_tx).await);
         replica.init_all().await?;
         // replica.tree(false).await;
+        let poll_replica = replica.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(config().poll_interval).await;
+                if let Err(e) = poll_replica.handle_poll().await {
+                    BannerOut::cross(format!("Polling failed : {}", e));
+                }
+            }
+        });
+        let notifier = Notifier::default();
+        notifier.start(changes_rx, replica.clone(), serve_addr.clone());
         let peer_server = PeerServer {
             replica: replica.clone(),
             channels: Arc::new(RwLock::new(HashMap::new())),
+            notifier,
         };
         let service_handle = tokio::spawn(async {
             Server::builder()
@@ -54,7 +76,7 @@ impl Reptra {
                 .await
         });
         Ok(Self {
-            id,
+            id: conf.id,
             serve_addr,
             service_handle,
             replica,
@@ -62,22 +84,49 @@ impl Reptra {
         })
     }
 
-    pub async fn send_port(&self, centra_addr: &ServeAddr) -> MyResult<()> {
+    pub async fn send_port(&self, centra_addr: &ServeAddr) -> TraResult<()> {
         let channel = channel_connect(centra_addr).await?;
         let mut port_sender = PortCollectClient::new(channel);
-        let msg = PortNumber {
-            id: self.id,
-            port: self.serve_addr.port() as i32,
-        };
         port_sender
-            .send_port(Request::new(msg))
+            .send_port(Request::new(self.port_number()))
             .await
-            .map_err(|e| "failed to send port : ".to_string() + &e.to_string())?;
+            .map_err(|status| TraError::from_status("Send Port", &status))?;
         Ok(())
     }
 
+    // keep the centra aware of this replica, registering again after the centra restarts
+    pub fn keep_alive(&self, centra_addr: &ServeAddr) {
+        let msg = self.port_number();
+        let centra_addr = centra_addr.clone();
+        tokio::spawn(async move {
+            let mut port_sender = None;
+            loop {
+                tokio::time::sleep(config().heartbeat_interval).await;
+                if port_sender.is_none() {
+                    port_sender = channel_connect(&centra_addr)
+                        .await
+                        .ok()
+                        .map(PortCollectClient::new);
+                }
+                if let Some(sender) = port_sender.as_mut() {
+                    if sender.heartbeat(Request::new(msg.clone())).await.is_err() {
+                        port_sender = None;
+                    }
+                }
+            }
+        });
+    }
+
+    fn port_number(&self) -> PortNumber {
+        PortNumber {
+            id: self.id,
+            port: self.serve_addr.port() as i32,
+            host: self.serve_addr.host().to_string(),
+        }
+    }
+
     pub async fn watching(&self) -> ! {
-        let mut buffer = [0; CHANNEL_BUFFER_SIZE];
+        let mut buffer = vec![0; config().channel_buffer_size];
         loop {
             let events = self
                 .file_watcher
@@ -86,11 +135,15 @@ impl Reptra {
                 .read_events_blocking(buffer.as_mut())
                 .unwrap();
             for event in events {
-                if event.mask != EventMask::IGNORED
-                    && !self.file_watcher.borrow().is_freezed(&event.wd).await
-                {
+                // an overflow has no watch, and is never skipped
+                let frozen = !event.mask.contains(EventMask::Q_OVERFLOW)
+                    && self.file_watcher.borrow().is_freezed(&event.wd).await;
+                if event.mask != EventMask::IGNORED && !frozen {
                     // self.file_watcher.borrow().display_event(&event).await;
-                    self.replica.handle_event(&event).await.unwrap();
+                    // a broken event should not kill the replica
+                    if let Err(e) = self.replica.handle_event(&event).await {
+                        BannerOut::cross(format!("Event Handling failed : {}", e));
+                    }
                     // self.replica.tree(true).await;
                 }
             }
@@ -98,7 +151,104 @@ impl Reptra {
     }
 }
 
-pub async fn reptra_greet_test(id: i32, centra_addr: &ServeAddr) -> MyResult<()> {
+// how a requested sync runs
+#[derive(Debug, Clone, Default)]
+pub struct SyncParams {
+    pub dry_run: bool,
+    // to cancel the sync by, the callee picks one if empty
+    pub session: String,
+    // None for the default of the callee
+    pub timeout: Option<Duration>,
+    // seeding a replica, which adopts the peer's versions instead of conflicting
+    pub join: bool,
+    // the conflicts decided before, by the path whether to take the peer's version
+    pub decided: HashMap<String, bool>,
+    // a background sync, which never prompts for a conflict
+    pub unattended: bool,
+}
+
+// ask the replica behind `client` to pull the path from the replica at `from`
+pub async fn request_sync(
+    client: &mut RsyncClient<RpcChannel>,
+    from: &ServeAddr,
+    root: String,
+    path_rel: String,
+    params: &SyncParams,
+) -> TraResult<SyncReport> {
+    let request = Request::new(SyncReq {
+        port: from.port() as i32,
+        path_rel,
+        root,
+        host: from.host().to_string(),
+        dry_run: params.dry_run,
+        session: params.session.clone(),
+        timeout: params.timeout.map_or(0, |timeout| timeout.as_secs()),
+        join: params.join,
+        decided: params.decided.clone(),
+        unattended: params.unattended,
+    });
+    client
+        .request_sync(request)
+        .await
+        .map(|res| res.into_inner())
+        .map_err(|status| TraError::from_status("Sync Failed", &status))
+}
+
+// ask the empty replica behind `client` to clone the root of the replica at `from`
+pub async fn request_clone(
+    client: &mut RsyncClient<RpcChannel>,
+    from: &ServeAddr,
+    root: String,
+    session: String,
+) -> TraResult<SyncReport> {
+    let request = Request::new(CloneReq {
+        root,
+        host: from.host().to_string(),
+        port: from.port() as i32,
+        session,
+    });
+    client
+        .request_clone(request)
+        .await
+        .map(|res| res.into_inner())
+        .map_err(|status| TraError::from_status("Clone Failed", &status))
+}
+
+// stop the session at its next file, if it is running on the replica behind `client`
+pub async fn request_cancel(client: &mut RsyncClient<RpcChannel>, session: &str) -> TraResult<()> {
+    let request = Request::new(CancelReq {
+        session: session.to_string(),
+    });
+    client
+        .cancel_sync(request)
+        .await
+        .map_err(|status| TraError::from_status("Cancel Sync", &status))?;
+    Ok(())
+}
+
+// ask the replica behind `client` to notify the replica `id` at `to` of the changes under the path
+pub async fn request_subscribe(
+    client: &mut RsyncClient<RpcChannel>,
+    id: i32,
+    to: &ServeAddr,
+    root: String,
+    path_rel: String,
+) -> TraResult<()> {
+    let request = Request::new(SubscribeReq {
+        id,
+        host: to.host().to_string(),
+        port: to.port() as i32,
+        root,
+        path_rel,
+    });
+    client
+        .subscribe(request)
+        .await
+        .map_err(|status| TraError::from_status("Subscribe", &status))?;
+    Ok(())
+}
+
+pub async fn reptra_greet_test(id: i32, centra_addr: &ServeAddr) -> TraResult<()> {
     let channel = channel_connect(centra_addr).await?;
     let mut client = GreeterClient::new(channel);
     for i in 0..3 {
@@ -0,0 +1,141 @@
+use std::{collections::BTreeSet, sync::Arc};
+
+use tokio::{sync::RwLock, time::Instant};
+use tonic::Request;
+
+use crate::{
+    banner::NotifyBanner,
+    checker::normal_path,
+    config::{config, MpscReceiver},
+    error::TraResult,
+    machine::{channel_connect, retry_rpc, ServeAddr},
+    replica::Replica,
+};
+
+use super::{ChangeNote, RsyncClient};
+
+// a local modification waiting to be announced
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Change {
+    pub root: String,
+    pub path_rel: String,
+}
+
+// a peer pulling the changes under a subtree of this replica
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subscriber {
+    pub id: i32,
+    pub addr: ServeAddr,
+    pub root: String,
+    pub path_rel: String,
+}
+
+impl Subscriber {
+    fn covers(&self, change: &Change) -> bool {
+        self.root == change.root && is_under(&change.path_rel, &self.path_rel)
+    }
+}
+
+// the subscribers are kept in memory, they subscribe again after this replica restarts
+#[derive(Clone, Default)]
+pub struct Notifier {
+    subscribers: Arc<RwLock<Vec<Subscriber>>>,
+}
+
+impl Notifier {
+    pub async fn subscribe(&self, subscriber: Subscriber) {
+        let mut subscribers = self.subscribers.write().await;
+        if !subscribers.contains(&subscriber) {
+            NotifyBanner::subscribed(&subscriber);
+            subscribers.push(subscriber);
+        }
+    }
+
+    // collect the changes until a quiet period, then announce them at once,
+    // a file changing all the time is still announced every few quiet periods
+    pub fn start(
+        &self,
+        mut changes: MpscReceiver<Change>,
+        replica: Arc<Replica>,
+        origin: ServeAddr,
+    ) {
+        let notifier = self.clone();
+        tokio::spawn(async move {
+            let quiet = config().notify_quiet;
+            while let Some(change) = changes.recv().await {
+                let mut pending = BTreeSet::from([change]);
+                let latest = Instant::now() + quiet * 10;
+                loop {
+                    let deadline = (Instant::now() + quiet).min(latest);
+                    match tokio::time::timeout_at(deadline, changes.recv()).await {
+                        Ok(Some(change)) => {
+                            pending.insert(change);
+                        }
+                        _ => break,
+                    }
+                }
+                notifier.announce(pending, &replica, &origin).await;
+            }
+        });
+    }
+
+    async fn announce(&self, pending: BTreeSet<Change>, replica: &Replica, origin: &ServeAddr) {
+        let subscribers = self.subscribers.read().await.clone();
+        for subscriber in subscribers {
+            // the ancestors are sorted before the descendants, so only the tops are kept
+            let mut tops: Vec<&Change> = Vec::new();
+            for change in pending.iter().filter(|change| subscriber.covers(change)) {
+                if !tops
+                    .iter()
+                    .any(|top| is_under(&change.path_rel, &top.path_rel))
+                {
+                    tops.push(change);
+                }
+            }
+            if tops.is_empty() {
+                continue;
+            }
+            if let Err(e) = notify(&subscriber, &tops, replica, origin).await {
+                NotifyBanner::dropped(&subscriber, &e);
+                self.subscribers
+                    .write()
+                    .await
+                    .retain(|other| other != &subscriber);
+            }
+        }
+    }
+}
+
+async fn notify(
+    subscriber: &Subscriber,
+    changes: &[&Change],
+    replica: &Replica,
+    origin: &ServeAddr,
+) -> TraResult<()> {
+    let client = RsyncClient::new(channel_connect(&subscriber.addr).await?);
+    for change in changes {
+        // only the failures of the subscriber drop it
+        let Ok(local) = replica.handle_query(&change.root, &change.path_rel, false).await else {
+            continue;
+        };
+        retry_rpc("Notify Changed", || {
+            let mut client = client.clone();
+            let request = Request::new(ChangeNote {
+                id: replica.id,
+                host: origin.host().to_string(),
+                port: origin.port() as i32,
+                root: change.root.clone(),
+                path_rel: change.path_rel.clone(),
+                mod_time: local.mod_time.clone(),
+            });
+            async move { client.notify_changed(request).await }
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+// whether the path is the ancestor itself or under it, "" and "." are the root
+fn is_under(path: &str, ancestor: &str) -> bool {
+    normal_path(path).starts_with(normal_path(ancestor))
+}
@@ -1,4 +1,6 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, pin::Pin, sync::Arc, time::Duration};
+
+use futures::Stream;
 
 use fast_rsync::{diff, Signature};
 
@@ -6,22 +8,34 @@ use tokio::sync::RwLock;
 use tonic::{Request, Response, Status};
 
 use crate::{
-    config::RpcChannel,
+    banner::{sync_path, NotifyBanner},
+    config::{config, RpcChannel},
+    error::{ErrorKind, TraError, TraResult},
     machine::{channel_connect, ServeAddr},
-    replica::{meta::read_bytes, path_local::PathLocal, Replica},
+    replica::{
+        session::{Session, PROGRESS_TICK},
+        Replica,
+    },
     reptra::FetchPatchReq,
-    MyResult,
+    timestamp::VectorTime,
 };
 
-use super::{Patch, QueryReq, QueryRes, Rsync, RsyncClient, SyncReq, Void};
+use super::{
+    notifier::{Notifier, Subscriber},
+    CancelReq, ChangeNote, CloneReq, Patch, Progress, ProgressReq, QueryReq, QueryRes, Rsync,
+    RsyncClient, StatusRes, SubscribeReq, SyncParams, SyncReport, SyncReq, TreeEntry, TreeReq,
+    Void,
+};
 
+#[derive(Clone)]
 pub struct PeerServer {
     pub replica: Arc<Replica>,
     pub channels: Arc<RwLock<HashMap<ServeAddr, RpcChannel>>>,
+    pub notifier: Notifier,
 }
 
 impl PeerServer {
-    pub async fn get_channel(&self, serve_addr: &ServeAddr) -> MyResult<RpcChannel> {
+    pub async fn get_channel(&self, serve_addr: &ServeAddr) -> TraResult<RpcChannel> {
         let mut inner = self.channels.write().await;
         if inner.get(&serve_addr).is_some() {
             return Ok(inner.get(&serve_addr).unwrap().clone());
@@ -30,6 +44,51 @@ impl PeerServer {
         inner.insert(serve_addr.clone(), channel.clone());
         return Ok(channel);
     }
+
+    pub async fn evict_channel(&self, serve_addr: &ServeAddr) {
+        self.channels.write().await.remove(serve_addr);
+    }
+
+    // pull the path from the replica at `from`
+    pub async fn pull(
+        &self,
+        root: &str,
+        path_rel: &str,
+        from: &ServeAddr,
+        params: SyncParams,
+    ) -> TraResult<SyncReport> {
+        let session = match params.session.is_empty() {
+            true => Session::new_id(),
+            false => params.session.clone(),
+        };
+        let timeout = params.timeout.or(config().sync_timeout);
+        let client = RsyncClient::new(self.get_channel(from).await?);
+        let session = self
+            .replica
+            .sessions
+            .start(session, sync_path(root, path_rel), timeout)?;
+        let _cancel = session.cancel_on_drop();
+        // the sync runs on its own, so that a dropped request can not interrupt it halfway
+        let replica = self.replica.clone();
+        let (root, path_rel) = (root.to_string(), path_rel.to_string());
+        let res = tokio::spawn(async move {
+            let res = replica
+                .handle_sync(&root, &path_rel, client, &params, session.clone())
+                .await;
+            replica.sessions.finish(&session.id);
+            res
+        })
+        .await
+        .map_err(|e| TraError::internal("Sync", e))?;
+        if res
+            .as_ref()
+            .is_err_and(|e| e.kind == ErrorKind::Unavailable)
+        {
+            // the next sync from this peer starts with a fresh connection
+            self.evict_channel(from).await;
+        }
+        res
+    }
 }
 
 #[tonic::async_trait]
@@ -37,39 +96,125 @@ impl Rsync for PeerServer {
     /// get the signature and diff, send back delta
     async fn fetch_patch(&self, req: Request<FetchPatchReq>) -> Result<Response<Patch>, Status> {
         let inner = req.into_inner();
-        let path = PathLocal::new_from_rel(&self.replica.base_node.path.prefix(), &inner.path_rel);
+        let root = self.replica.root(&inner.root)?;
+        let path = root.request_path("Fetch Patch", &inner.path_rel)?;
         let sig = Signature::deserialize(inner.sig)
-            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+            .map_err(|e| TraError::invalid("Fetch Patch", e).at(&path))?;
         let index_sig = sig.index();
-        let data = read_bytes(&path)
-            .await
-            .map_err(|e| Status::invalid_argument(e))?;
+        let data = root.read_unchanged(&path, &inner.mod_time.into()).await?;
         let mut delta: Vec<u8> = Vec::new();
-        diff(&index_sig, &data, &mut delta).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        diff(&index_sig, &data, &mut delta)
+            .map_err(|e| TraError::internal("Fetch Patch", e).at(&path))?;
         Ok(Response::new(Patch { delta }))
     }
 
     /// query the info of one file(dir)
     async fn query(&self, req: Request<QueryReq>) -> Result<Response<QueryRes>, Status> {
+        let inner = req.into_inner();
         let res = self
             .replica
-            .handle_query(&req.into_inner().path_rel)
-            .await
-            .map_err(|e| Status::invalid_argument(e))?;
+            .handle_query(&inner.root, &inner.path_rel, inner.hash)
+            .await?;
         Ok(Response::new(res))
     }
 
-    async fn request_sync(&self, req: Request<SyncReq>) -> Result<Response<Void>, Status> {
+    async fn request_sync(&self, req: Request<SyncReq>) -> Result<Response<SyncReport>, Status> {
         let inner = req.into_inner();
-        let query_channel = self
-            .get_channel(&ServeAddr::new(inner.port as u16))
-            .await
-            .map_err(|e| Status::invalid_argument(e))?;
-        let client = RsyncClient::new(query_channel);
-        self.replica
-            .handle_sync(&inner.path_rel, client)
-            .await
-            .map_err(|e| Status::invalid_argument(e))?;
+        let serve_addr = ServeAddr::new(&inner.host, inner.port as u16);
+        let params = SyncParams {
+            dry_run: inner.dry_run,
+            session: inner.session,
+            timeout: match inner.timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            join: inner.join,
+            decided: inner.decided,
+            unattended: inner.unattended,
+        };
+        let report = self
+            .pull(&inner.root, &inner.path_rel, &serve_addr, params)
+            .await?;
+        Ok(Response::new(report))
+    }
+
+    type SyncProgressStream = Pin<Box<dyn Stream<Item = Result<Progress, Status>> + Send>>;
+
+    /// a snapshot of the session every tick, until the one after it is done
+    async fn sync_progress(
+        &self,
+        req: Request<ProgressReq>,
+    ) -> Result<Response<Self::SyncProgressStream>, Status> {
+        let id = req.into_inner().session;
+        let session = self.replica.sessions.wait_for(&id).await?;
+        let stream = futures::stream::unfold(Some(session), |session| async move {
+            let session = session?;
+            tokio::time::sleep(PROGRESS_TICK).await;
+            let progress = session.progress();
+            let next = (!progress.done).then_some(session);
+            Some((Ok(progress), next))
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn request_clone(&self, req: Request<CloneReq>) -> Result<Response<SyncReport>, Status> {
+        let inner = req.into_inner();
+        let from = ServeAddr::new(&inner.host, inner.port as u16);
+        let client = RsyncClient::new(self.get_channel(&from).await?);
+        let session = match inner.session.is_empty() {
+            true => Session::new_id(),
+            false => inner.session,
+        };
+        let session = self
+            .replica
+            .sessions
+            .start(session, sync_path(&inner.root, ""), None)?;
+        let _cancel = session.cancel_on_drop();
+        // like a sync, a dropped request only cancels the clone, which then cleans up
+        let replica = self.replica.clone();
+        let root = inner.root;
+        let res = tokio::spawn(async move {
+            let res = replica.handle_clone(&root, client, session.clone()).await;
+            replica.sessions.finish(&session.id);
+            res
+        })
+        .await
+        .map_err(|e| TraError::internal("Clone", e))?;
+        if res
+            .as_ref()
+            .is_err_and(|e| e.kind == ErrorKind::Unavailable)
+        {
+            self.evict_channel(&from).await;
+        }
+        Ok(Response::new(res?))
+    }
+
+    type FetchTreeStream = Pin<Box<dyn Stream<Item = Result<TreeEntry, Status>> + Send>>;
+
+    /// the entries are sent as they are read, an error ends the stream
+    async fn fetch_tree(
+        &self,
+        req: Request<TreeReq>,
+    ) -> Result<Response<Self::FetchTreeStream>, Status> {
+        let root = req.into_inner().root;
+        // fails early on an unknown root
+        self.replica.root(&root)?;
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        let replica = self.replica.clone();
+        tokio::spawn(async move {
+            if let Err(e) = replica.send_tree(&root, &tx).await {
+                let _ = tx.send(Err(e.into())).await;
+            }
+        });
+        let stream = futures::stream::unfold(rx, |mut rx| async move {
+            let entry = rx.recv().await?;
+            Some((entry, rx))
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn cancel_sync(&self, req: Request<CancelReq>) -> Result<Response<Void>, Status> {
+        self.replica.sessions.cancel(&req.into_inner().session)?;
         Ok(Response::new(Void {}))
     }
 
@@ -77,4 +222,50 @@ impl Rsync for PeerServer {
         self.replica.tree(true).await;
         Ok(Response::new(Void {}))
     }
+
+    async fn status(&self, _req: Request<Void>) -> Result<Response<StatusRes>, Status> {
+        Ok(Response::new(self.replica.handle_status().await))
+    }
+
+    async fn subscribe(&self, req: Request<SubscribeReq>) -> Result<Response<Void>, Status> {
+        let inner = req.into_inner();
+        let root = self.replica.root(&inner.root)?;
+        root.request_path("Subscribe", &inner.path_rel)?;
+        let subscriber = Subscriber {
+            id: inner.id,
+            addr: ServeAddr::new(&inner.host, inner.port as u16),
+            root: inner.root,
+            path_rel: inner.path_rel,
+        };
+        self.notifier.subscribe(subscriber).await;
+        Ok(Response::new(Void {}))
+    }
+
+    /// schedule a pull of the changed path, unless it is already known here
+    async fn notify_changed(&self, req: Request<ChangeNote>) -> Result<Response<Void>, Status> {
+        let remote = req.remote_addr();
+        let note = req.into_inner();
+        let from = ServeAddr::from_sender(&note.host, note.port as u16, remote)?;
+        let local = self
+            .replica
+            .handle_query(&note.root, &note.path_rel, false)
+            .await?;
+        let mod_time: VectorTime = note.mod_time.into();
+        if mod_time.leq(&local.sync_time.into()) {
+            return Ok(Response::new(Void {}));
+        }
+        let server = self.clone();
+        tokio::spawn(async move {
+            NotifyBanner::pull(note.id, &from, &note.root, &note.path_rel);
+            let params = SyncParams {
+                unattended: true,
+                ..Default::default()
+            };
+            match server.pull(&note.root, &note.path_rel, &from, params).await {
+                Ok(report) => NotifyBanner::pulled(note.id, &note.root, &note.path_rel, &report),
+                Err(e) => NotifyBanner::pull_failed(note.id, &note.root, &note.path_rel, e),
+            }
+        });
+        Ok(Response::new(Void {}))
+    }
 }